@@ -0,0 +1,283 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// The archive formats members can be read from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+impl ArchiveFormat {
+    /// The format of the archive `path`, from its extension, if it is an archive.
+    pub fn of(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?.to_lowercase();
+        if name.ends_with(".zip") {
+            Some(ArchiveFormat::Zip)
+        } else if name.ends_with(".tar") {
+            Some(ArchiveFormat::Tar)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(ArchiveFormat::TarGz)
+        } else {
+            None
+        }
+    }
+}
+
+/// The position and size of the data of each member of a tar archive
+type TarIndex = HashMap<String, (u64, u64)>;
+
+/// The tar archives already listed, so their members can be found without scanning them again
+fn tar_indexes() -> &'static Mutex<HashMap<PathBuf, Arc<TarIndex>>> {
+    static TAR_INDEXES: OnceLock<Mutex<HashMap<PathBuf, Arc<TarIndex>>>> = OnceLock::new();
+    TAR_INDEXES.get_or_init(Default::default)
+}
+
+/// The archives opened last, shared by all the threads (DataFusion polls a stream on any
+/// of its worker threads), so an archive is not opened again for each of its members
+///
+/// Only a few archives are kept, each one locked while one of its members is read.
+struct ArchiveCache<T> {
+    archives: Mutex<VecDeque<(PathBuf, Arc<Mutex<T>>)>>,
+}
+
+impl<T> ArchiveCache<T> {
+    const CAPACITY: usize = 8;
+
+    const fn new() -> Self {
+        ArchiveCache { archives: Mutex::new(VecDeque::new()) }
+    }
+
+    /// The cached archive `path`, or the one `open` returns, cached as the last one used.
+    fn get(&self, path: &Path, open: impl FnOnce() -> io::Result<T>) -> io::Result<Arc<Mutex<T>>> {
+        let mut archives = self.archives.lock().unwrap();
+        let archive = match archives.iter().position(|(cached_path, _)| cached_path == path) {
+            Some(index) => archives.remove(index).unwrap().1,
+            None => Arc::new(Mutex::new(open()?)),
+        };
+        archives.push_front((path.to_path_buf(), archive.clone()));
+        archives.truncate(Self::CAPACITY);
+        Ok(archive)
+    }
+}
+
+/// The zip archives opened, so their central directory is not parsed for every member.
+static ZIP_ARCHIVES: ArchiveCache<zip::ZipArchive<BufReader<File>>> = ArchiveCache::new();
+
+/// A decompressed tar.gz stream and the position it is at, so reading the members in order
+/// decompresses the archive only once, with the last member read (a file is read several
+/// times when it is opened, to sniff it then for its header, and the stream can not be
+/// sought back).
+struct TarGzStream {
+    position: u64,
+    stream: Box<dyn Read + Send>,
+    member: Option<(String, Vec<u8>)>,
+}
+
+static TAR_GZ_STREAMS: ArchiveCache<TarGzStream> = ArchiveCache::new();
+
+/// The name of a member, without the `./` tar adds when archiving a directory as `.`, so the
+/// paths are the same as the ones of the files extracted.
+fn member_name(name: &str) -> &str {
+    let mut name = name;
+    while let Some(rest) = name.strip_prefix("./") {
+        name = rest.trim_start_matches('/');
+    }
+    name
+}
+
+fn tar_stream(path: &Path, format: ArchiveFormat) -> io::Result<Box<dyn Read + Send>> {
+    let file = BufReader::new(File::open(path)?);
+    match format {
+        ArchiveFormat::TarGz => Ok(Box::new(flate2::read::GzDecoder::new(file))),
+        _ => Ok(Box::new(file)),
+    }
+}
+
+/// The names of the files in the archive `path`, sorted.
+pub fn members(path: &Path) -> io::Result<Vec<String>> {
+    let format = ArchiveFormat::of(path).ok_or_else(|| io::Error::other(format!("{} is not an archive", path.display())))?;
+    let mut result = match format {
+        ArchiveFormat::Zip => {
+            let archive = zip::ZipArchive::new(BufReader::new(File::open(path)?))?;
+            archive.file_names()
+                   .filter(|x| !x.ends_with('/'))
+                   .map(|x| x.to_string())
+                   .collect::<Vec<_>>()
+        }
+        ArchiveFormat::Tar | ArchiveFormat::TarGz => {
+            let index = tar_index(path, format)?;
+            index.keys().cloned().collect()
+        }
+    };
+    result.sort();
+    Ok(result)
+}
+
+fn tar_index(path: &Path, format: ArchiveFormat) -> io::Result<Arc<TarIndex>> {
+    if let Some(index) = tar_indexes().lock().unwrap().get(path) {
+        return Ok(index.clone());
+    }
+    let mut index = TarIndex::new();
+    for entry in tar::Archive::new(tar_stream(path, format)?).entries()? {
+        let entry = entry?;
+        if entry.header().entry_type().is_file() {
+            index.insert(member_name(&entry.path()?.to_string_lossy()).to_string(), (entry.raw_file_position(), entry.size()));
+        }
+    }
+    let index = Arc::new(index);
+    tar_indexes().lock().unwrap().insert(path.to_path_buf(), index.clone());
+    Ok(index)
+}
+
+/// The content of the file `member` of the archive `path`
+///
+/// The members of a tar.gz archive are read from the same decompressed stream while they
+/// are in the order of the archive, which is restarted when going back to a previous one.
+pub fn read_member(path: &Path, member: &str) -> io::Result<Vec<u8>> {
+    let format = ArchiveFormat::of(path).ok_or_else(|| io::Error::other(format!("{} is not an archive", path.display())))?;
+    let mut result = Vec::new();
+    match format {
+        ArchiveFormat::Zip => {
+            let archive = ZIP_ARCHIVES.get(path, || Ok(zip::ZipArchive::new(BufReader::new(File::open(path)?))?))?;
+            archive.lock().unwrap().by_name(member)?.read_to_end(&mut result)?;
+        }
+        ArchiveFormat::Tar => {
+            let (position, size) = tar_member(path, format, member)?;
+            let mut file = File::open(path)?;
+            file.seek(SeekFrom::Start(position))?;
+            file.take(size).read_to_end(&mut result)?;
+        }
+        ArchiveFormat::TarGz => {
+            let (position, size) = tar_member(path, format, member)?;
+            let cached = TAR_GZ_STREAMS.get(path, || Ok(TarGzStream { position: 0, stream: tar_stream(path, format)?, member: None }))?;
+            let mut cached = cached.lock().unwrap();
+            if let Some((_, bytes)) = cached.member.as_ref().filter(|(cached_member, _)| cached_member == member) {
+                return Ok(bytes.clone());
+            }
+            // The stream is only restarted when going back to a member before the last one
+            if cached.position > position {
+                cached.stream = tar_stream(path, format)?;
+                cached.position = 0;
+            }
+            let skip = position - cached.position;
+            io::copy(&mut (&mut cached.stream).take(skip), &mut io::sink())?;
+            (&mut cached.stream).take(size).read_to_end(&mut result)?;
+            cached.position = position + size;
+            cached.member = Some((member.to_string(), result.clone()));
+        }
+    }
+    Ok(result)
+}
+
+fn tar_member(path: &Path, format: ArchiveFormat, member: &str) -> io::Result<(u64, u64)> {
+    tar_index(path, format)?.get(member)
+                            .copied()
+                            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound,
+                                                          format!("{} not found in {}", member, path.display())))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use crate::reader::DicomReader;
+    use super::*;
+
+    const FILES: [&str; 2] = ["1-001.dcm", "1-002.dcm"];
+
+    fn content(file: &str) -> Vec<u8> {
+        std::fs::read(Path::new("data/tciaDownload/pat1").join(file)).unwrap()
+    }
+
+    /// A tar archive of the files in `series/`, named `./series/...` like `tar -cf - .` does.
+    fn tar() -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for file in FILES {
+            let content = content(file);
+            let mut header = tar::Header::new_gnu();
+            let name = format!("./series/{}", file);
+            header.as_gnu_mut().unwrap().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append(&header, content.as_slice()).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    /// A directory with the series in a zip, a tar and a tar.gz archive and unpacked.
+    fn archives() -> tempfile::TempDir {
+        let directory = tempfile::tempdir().unwrap();
+        let mut zip = zip::ZipWriter::new(File::create(directory.path().join("a.zip")).unwrap());
+        for file in FILES {
+            zip.start_file(format!("series/{}", file), zip::write::SimpleFileOptions::default()).unwrap();
+            zip.write_all(&content(file)).unwrap();
+        }
+        zip.finish().unwrap();
+        std::fs::write(directory.path().join("b.tar"), tar()).unwrap();
+        let mut tar_gz = flate2::write::GzEncoder::new(File::create(directory.path().join("c.tar.gz")).unwrap(),
+                                                       flate2::Compression::default());
+        tar_gz.write_all(&tar()).unwrap();
+        tar_gz.finish().unwrap();
+        std::fs::create_dir(directory.path().join("d")).unwrap();
+        for file in FILES {
+            std::fs::write(directory.path().join("d").join(file), content(file)).unwrap();
+        }
+        directory
+    }
+
+    #[test]
+    fn members_and_series() {
+        let directory = archives();
+        for archive in ["a.zip", "b.tar", "c.tar.gz"] {
+            let path = directory.path().join(archive);
+            assert_eq!(members(&path).unwrap(), vec!["series/1-001.dcm", "series/1-002.dcm"]);
+            // Back and forth, and the same member twice
+            for file in ["1-002.dcm", "1-001.dcm", "1-001.dcm", "1-002.dcm"] {
+                assert!(read_member(&path, &format!("series/{}", file)).unwrap() == content(file), "{} {}", archive, file);
+            }
+            assert_eq!(read_member(&path, "series/missing.dcm").unwrap_err().kind(), io::ErrorKind::NotFound);
+        }
+
+        let reader = DicomReader::new(directory.path()).unwrap();
+        let paths = (0..reader.len()).map(|index| reader.path(index)).collect::<Vec<_>>();
+        let root = directory.path().display();
+        assert_eq!(paths, vec![format!("{}/a.zip!/series", root), format!("{}/b.tar!/series", root),
+                               format!("{}/c.tar.gz!/series", root), format!("{}/d", root)]);
+        let voxels = reader.iter().map(|image| image.voxels()).collect::<Vec<_>>();
+        assert!(voxels.iter().all(|x| *x == voxels[3]));
+    }
+
+    #[test]
+    fn members_read_on_several_threads() {
+        let directory = archives();
+        let contents = FILES.map(content);
+        std::thread::scope(|scope| {
+            for thread in 0..4 {
+                let (directory, contents) = (&directory, &contents);
+                scope.spawn(move || {
+                    for round in 0..4 {
+                        for archive in ["a.zip", "b.tar", "c.tar.gz"] {
+                            let index = (thread + round) % 2;
+                            let member = read_member(&directory.path().join(archive), &format!("series/{}", FILES[index]));
+                            assert!(member.unwrap() == contents[index]);
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn unreadable_archive() {
+        let directory = tempfile::tempdir().unwrap();
+        std::fs::write(directory.path().join("a.zip"), b"Not a zip archive").unwrap();
+        assert!(DicomReader::new(directory.path()).is_err());
+        let mut streamer = crate::reader::DicomStreamer::new(directory.path()).with_projection(Some(vec!["path"]));
+        assert!(streamer.try_next_batch().is_err());
+    }
+}
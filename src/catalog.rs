@@ -0,0 +1,265 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use arrow::array::{Array, AsArray, Int32Array, Int64Array, RecordBatch, StringArray, UInt16Array, UInt64Array};
+use arrow::datatypes::{DataType, Field, Schema, Int32Type, Int64Type, UInt16Type, UInt64Type};
+use parquet::arrow::ArrowWriter;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use crate::file::{self, FileHeader, FileLocation, FileStart};
+use crate::metrics::ScanMetrics;
+
+/// What the catalog knows of a file, valid while its size and modification time do not change
+#[derive(Debug, Clone, PartialEq)]
+pub struct CatalogEntry {
+    pub size: u64,
+    /// Nanoseconds since the Unix epoch.
+    pub modified: i64,
+    /// How the file starts, `None` if it is not a DICOM file.
+    pub start: Option<FileStart>,
+    /// The header of the file, `None` if it could not be read.
+    pub header: Option<FileHeader>,
+}
+
+/// A persistent index of the files found when reading directories
+///
+/// The catalog is a Parquet file, with a row for each file (DICOM or not) in the directories
+/// read with it, so that scanning them again only opens the new or modified files (see
+/// `DiscoveryOptions::with_catalog`). Files are identified by their location, and the members
+/// of archives are considered modified when their archive is. Files in object stores or in
+/// memory are not cataloged.
+pub struct Catalog {
+    path: PathBuf,
+    entries: HashMap<String, CatalogEntry>,
+    /// The files found in this scan, the other ones under the root scanned were removed.
+    seen: HashSet<String>,
+}
+
+impl Catalog {
+    /// The catalog in `path`, or an empty one if it does not exist or can not be read
+    /// (as the ones written by older versions, without all the columns).
+    pub fn open(path: impl AsRef<Path>) -> Self {
+        Catalog {
+            path: path.as_ref().to_path_buf(),
+            entries: Catalog::load(path.as_ref()).unwrap_or_default(),
+            seen: HashSet::new(),
+        }
+    }
+
+    /// The path of the Parquet file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn load(path: &Path) -> Result<HashMap<String, CatalogEntry>, Box<dyn std::error::Error>> {
+        let mut entries = HashMap::new();
+        for batch in ParquetRecordBatchReaderBuilder::try_new(File::open(path)?)?.build()? {
+            let batch = batch?;
+            let column = |name| batch.column_by_name(name).ok_or(format!("Column {} not found in the catalog", name));
+            let locations = column("location")?.as_string::<i32>();
+            let sizes = column("size")?.as_primitive::<UInt64Type>();
+            let modified = column("modified")?.as_primitive::<Int64Type>();
+            let starts = column("start")?.as_string::<i32>();
+            let modalities = column("modality")?.as_string::<i32>();
+            let transfer_syntaxes = column("transfer_syntax")?.as_string::<i32>();
+            let columns = column("columns")?.as_primitive::<UInt16Type>();
+            let rows = column("rows")?.as_primitive::<UInt16Type>();
+            let bits_allocated = column("bits_allocated")?.as_primitive::<UInt16Type>();
+            let samples_per_pixel = column("samples_per_pixel")?.as_primitive::<UInt16Type>();
+            let study_dates = column("study_date")?.as_string::<i32>();
+            let series_numbers = column("series_number")?.as_primitive::<Int32Type>();
+            let series_instance_uids = column("series_instance_uid")?.as_string::<i32>();
+            let optional_str = |array: &StringArray, i| (!array.is_null(i)).then(|| array.value(i).to_string());
+
+            for i in 0..batch.num_rows() {
+                let header = (!modalities.is_null(i)).then(|| FileHeader {
+                    modality: modalities.value(i).to_string(),
+                    transfer_syntax: transfer_syntaxes.value(i).to_string(),
+                    columns: columns.value(i),
+                    rows: rows.value(i),
+                    bits_allocated: bits_allocated.value(i),
+                    samples_per_pixel: samples_per_pixel.value(i),
+                    study_date: optional_str(study_dates, i),
+                    series_number: (!series_numbers.is_null(i)).then(|| series_numbers.value(i)),
+                    series_instance_uid: optional_str(series_instance_uids, i),
+                });
+                entries.insert(locations.value(i).to_string(), CatalogEntry {
+                    size: sizes.value(i),
+                    modified: modified.value(i),
+                    start: if starts.is_null(i) { None } else { Some(parse_start(starts.value(i))?) },
+                    header,
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    /// The entry of `location`, read from the file if it is new or was modified (counted in `metrics`).
+    pub fn entry(&mut self, location: &FileLocation, metrics: &ScanMetrics) -> CatalogEntry {
+        let read_entry = |size, modified| metrics.open(&metrics.header_parse_time, || read_entry(location, size, modified));
+        let Some((size, modified)) = stat(location) else {
+            return read_entry(0, 0);
+        };
+        let key = location.to_string();
+        self.seen.insert(key.clone());
+        match self.entries.get(&key) {
+            Some(entry) if entry.size == size && entry.modified == modified => entry.clone(),
+            _ => {
+                let entry = read_entry(size, modified);
+                self.entries.insert(key, entry.clone());
+                entry
+            }
+        }
+    }
+
+    /// Write the catalog, without the files under `root` (the directory or archive scanned)
+    /// that were not found in this scan.
+    pub fn save(&mut self, root: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.entries.retain(|location, _| !is_under(location, root) || self.seen.contains(location));
+        let mut locations = self.entries.keys().collect::<Vec<_>>();
+        locations.sort();
+        let entries = locations.iter().map(|x| &self.entries[*x]).collect::<Vec<_>>();
+        let headers = entries.iter().map(|x| x.header.as_ref()).collect::<Vec<_>>();
+        let header_values = |f: fn(&FileHeader) -> u16| -> Arc<dyn Array> {
+            Arc::new(headers.iter().map(|x| x.map(f)).collect::<UInt16Array>())
+        };
+
+        let batch = RecordBatch::try_new(Arc::new(schema()), vec![
+            Arc::new(locations.iter().map(|x| Some(x.as_str())).collect::<StringArray>()),
+            Arc::new(entries.iter().map(|x| x.size).collect::<UInt64Array>()),
+            Arc::new(entries.iter().map(|x| x.modified).collect::<Int64Array>()),
+            Arc::new(entries.iter().map(|x| x.start.map(format_start)).collect::<StringArray>()),
+            Arc::new(headers.iter().map(|x| x.map(|x| x.modality.as_str())).collect::<StringArray>()),
+            Arc::new(headers.iter().map(|x| x.map(|x| x.transfer_syntax.as_str())).collect::<StringArray>()),
+            header_values(|x| x.columns),
+            header_values(|x| x.rows),
+            header_values(|x| x.bits_allocated),
+            header_values(|x| x.samples_per_pixel),
+            Arc::new(headers.iter().map(|x| x.and_then(|x| x.study_date.as_deref())).collect::<StringArray>()),
+            Arc::new(headers.iter().map(|x| x.and_then(|x| x.series_number)).collect::<Int32Array>()),
+            Arc::new(headers.iter().map(|x| x.and_then(|x| x.series_instance_uid.as_deref())).collect::<StringArray>()),
+        ])?;
+
+        let mut writer = ArrowWriter::try_new(File::create(&self.path)?, batch.schema(), None)?;
+        writer.write(&batch)?;
+        writer.close()?;
+        Ok(())
+    }
+}
+
+fn schema() -> Schema {
+    Schema::new(vec![
+        Field::new("location", DataType::Utf8, false),
+        Field::new("size", DataType::UInt64, false),
+        Field::new("modified", DataType::Int64, false),
+        Field::new("start", DataType::Utf8, true),
+        Field::new("modality", DataType::Utf8, true),
+        Field::new("transfer_syntax", DataType::Utf8, true),
+        Field::new("columns", DataType::UInt16, true),
+        Field::new("rows", DataType::UInt16, true),
+        Field::new("bits_allocated", DataType::UInt16, true),
+        Field::new("samples_per_pixel", DataType::UInt16, true),
+        Field::new("study_date", DataType::Utf8, true),
+        Field::new("series_number", DataType::Int32, true),
+        Field::new("series_instance_uid", DataType::Utf8, true),
+    ])
+}
+
+/// Whether `location` is `root`, or in it as a directory (`root/...`) or an archive (`root!/...`).
+fn is_under(location: &str, root: &str) -> bool {
+    let root = root.trim_end_matches('/');
+    location.strip_prefix(root)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/') || rest.starts_with("!/"))
+}
+
+/// The size and modification time of a file, if it is in the file system.
+fn stat(location: &FileLocation) -> Option<(u64, i64)> {
+    let path = match location {
+        FileLocation::Path(path) => path,
+        FileLocation::ArchiveMember { archive, .. } => archive,
+        _ => return None,
+    };
+    let metadata = std::fs::metadata(path).ok()?;
+    let modified = metadata.modified().ok()?.duration_since(std::time::UNIX_EPOCH).ok()?;
+    Some((metadata.len(), modified.as_nanos() as i64))
+}
+
+fn read_entry(location: &FileLocation, size: u64, modified: i64) -> CatalogEntry {
+    let start = file::sniff(location);
+    CatalogEntry {
+        size,
+        modified,
+        start,
+        header: start.and_then(|_| FileHeader::read(location).ok()),
+    }
+}
+
+fn format_start(start: FileStart) -> &'static str {
+    match start {
+        FileStart::Preamble => "preamble",
+        FileStart::Magic => "magic",
+        FileStart::MetaGroup => "meta_group",
+        FileStart::DataSet => "data_set",
+    }
+}
+
+fn parse_start(value: &str) -> Result<FileStart, String> {
+    match value {
+        "preamble" => Ok(FileStart::Preamble),
+        "magic" => Ok(FileStart::Magic),
+        "meta_group" => Ok(FileStart::MetaGroup),
+        "data_set" => Ok(FileStart::DataSet),
+        _ => Err(format!("Unknown file start in the catalog: {}", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locations_under_root() {
+        assert!(is_under("/data/a/s/1.dcm", "/data/a"));
+        assert!(is_under("/data/a/s/1.dcm", "/data/a/"));
+        assert!(is_under("/data/a.zip!/s/1.dcm", "/data/a.zip"));
+        assert!(is_under("/data/a", "/data/a"));
+        assert!(!is_under("/data/ab/s/1.dcm", "/data/a"));
+        assert!(!is_under("/data/a.zip!/s/1.dcm", "/data/a"));
+    }
+
+    #[test]
+    fn scans_of_sibling_archives() {
+        use std::io::Write;
+        use crate::discovery::DiscoveryOptions;
+        use crate::reader::DicomReader;
+
+        let directory = tempfile::tempdir().unwrap();
+        for archive in ["a.zip", "b.zip"] {
+            let mut zip = zip::ZipWriter::new(File::create(directory.path().join(archive)).unwrap());
+            for file in ["1-001.dcm", "1-002.dcm"] {
+                zip.start_file(format!("series/{}", file), zip::write::SimpleFileOptions::default()).unwrap();
+                zip.write_all(&std::fs::read(Path::new("data/tciaDownload/pat1").join(file)).unwrap()).unwrap();
+            }
+            zip.finish().unwrap();
+        }
+        let catalog = tempfile::tempdir().unwrap();
+        let discovery = DiscoveryOptions::default().with_catalog(Some(catalog.path().join("catalog.parquet")));
+        let scan = |archive: &str| {
+            let metrics = ScanMetrics::new();
+            let reader = DicomReader::with_discovery_metrics(directory.path().join(archive), &discovery, metrics.clone()).unwrap();
+            assert_eq!(reader.len(), 1);
+            metrics.files_opened.value()
+        };
+        assert_eq!(scan("a.zip"), 2);
+        assert_eq!(scan("b.zip"), 2);
+        // Scanning an archive keeps the entries of the other one
+        let mut locations = Catalog::open(catalog.path().join("catalog.parquet")).entries.into_keys().collect::<Vec<_>>();
+        locations.sort();
+        let root = directory.path().display();
+        assert_eq!(locations, vec![format!("{}/a.zip!/series/1-001.dcm", root), format!("{}/a.zip!/series/1-002.dcm", root),
+                                   format!("{}/b.zip!/series/1-001.dcm", root), format!("{}/b.zip!/series/1-002.dcm", root)]);
+        assert_eq!(scan("a.zip"), 0);
+        assert_eq!(scan("b.zip"), 0);
+    }
+}
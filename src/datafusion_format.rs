@@ -0,0 +1,339 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use async_trait::async_trait;
+use arrow::datatypes::{DataType, Schema, SchemaRef};
+use futures::TryStreamExt;
+use object_store::{ObjectMeta, ObjectStore};
+use object_store::path::Path as ObjectPath;
+use datafusion::datasource::file_format::FileFormat;
+use datafusion::datasource::listing::{ListingOptions, ListingTable, ListingTableConfig, ListingTableUrl};
+use datafusion::datasource::physical_plan::FileScanConfig;
+use datafusion::datasource::provider::TableProviderFactory;
+use datafusion::datasource::{TableProvider, TableType};
+use datafusion::common::Statistics;
+use datafusion::error::DataFusionError;
+use datafusion::execution::context::SessionState;
+use datafusion::physical_expr::PhysicalExpr;
+use datafusion::physical_plan::ExecutionPlan;
+use datafusion_expr::{CreateExternalTable, Expr, TableProviderFilterPushDown};
+use crate::datafusion_reader::DicomExecutionPlan;
+use crate::file::FileLocation;
+use crate::reader::DicomOptions;
+use crate::storage::{DicomSource, StoreRef};
+
+/// DICOM files as a DataFusion `FileFormat`, to read them with a `ListingTable`
+///
+/// The files listed by the table are discovered as the ones of a directory (see
+/// `DiscoveryOptions`, the files that are not DICOM are ignored), and grouped in series in a
+/// single partition, as the files of a series may be in different file groups of the table.
+/// The files of the local file system are read, and shown in the `path` column, by their path.
+#[derive(Debug, Clone, Default)]
+pub struct DicomFormat {
+    options: DicomOptions,
+    root: String,
+}
+
+impl DicomFormat {
+    pub fn new(options: DicomOptions) -> Self {
+        DicomFormat {
+            options,
+            root: String::new(),
+        }
+    }
+
+    /// The url of the table, the discovery patterns with a `/` are relative to.
+    pub fn with_root(mut self, root: &str) -> Self {
+        self.root = local_path(root).unwrap_or(root).trim_end_matches('/').to_string();
+        self
+    }
+}
+
+/// The path of a `file://` url.
+fn local_path(url: &str) -> Option<&str> {
+    url.strip_prefix("file://").filter(|x| x.is_empty() || x.starts_with('/'))
+}
+
+/// The location of the object `path` of `store`, as the files of a directory for the local file
+/// system, so the `path` column is the same as with a `DicomTableProvider` of the directory.
+fn location(store: &StoreRef, path: ObjectPath) -> FileLocation {
+    match local_path(&store.base_url) {
+        Some(base_path) => FileLocation::Path(PathBuf::from(format!("{}/{}", base_path, path))),
+        None => FileLocation::Object { store: store.clone(), path },
+    }
+}
+
+#[async_trait]
+impl FileFormat for DicomFormat {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    async fn infer_schema(&self,
+                          _state: &SessionState,
+                          _store: &Arc<dyn ObjectStore>,
+                          _objects: &[ObjectMeta]) -> Result<SchemaRef, DataFusionError> {
+        Ok(Arc::new(self.options.schema()))
+    }
+
+    async fn infer_stats(&self,
+                         _state: &SessionState,
+                         _store: &Arc<dyn ObjectStore>,
+                         table_schema: SchemaRef,
+                         _object: &ObjectMeta) -> Result<Statistics, DataFusionError> {
+        // The rows are series, not files
+        Ok(Statistics::new_unknown(&table_schema))
+    }
+
+    async fn create_physical_plan(&self,
+                                  state: &SessionState,
+                                  conf: FileScanConfig,
+                                  filters: Option<&Arc<dyn PhysicalExpr>>) -> Result<Arc<dyn ExecutionPlan>, DataFusionError> {
+        let url = conf.object_store_url.as_str();
+        let store = StoreRef {
+            store: state.runtime_env().object_store(&conf.object_store_url)?,
+            base_url: url.strip_suffix('/').unwrap_or(url).to_string(),
+        };
+        let mut files = Vec::new();
+        let mut partition_values = HashMap::new();
+        for file in conf.file_groups.into_iter().flatten() {
+            let location = location(&store, file.object_meta.location);
+            partition_values.insert(location.to_string(), file.partition_values);
+            files.push(location);
+        }
+        let mut fields = conf.file_schema.fields().iter().map(|x| x.as_ref().clone()).collect::<Vec<_>>();
+        fields.extend(conf.table_partition_cols);
+
+        Ok(Arc::new(DicomExecutionPlan::new(DicomSource::Files { root: self.root.clone(), files },
+                                            Arc::new(Schema::new(fields)),
+                                            conf.projection.as_ref(),
+                                            conf.limit,
+                                            self.options.clone(),
+                                            partition_values,
+                                            filters)?))
+    }
+}
+
+/// Creates the tables of `CREATE EXTERNAL TABLE ... STORED AS DICOM LOCATION '...'`
+///
+/// The factory has to be registered for the `DICOM` type in the session (see the README).
+/// The tables are `ListingTable`s of `DicomFormat`, with the options of
+/// `DicomOptions::with_option` (as `OPTIONS ('tags' 'PatientID,StudyDate', 'granularity' 'instance')`).
+///
+/// The partition columns are the ones of `PARTITIONED BY`, or the ones found in the
+/// hive-style directories of the DICOM files of the location (as `patient=.../study=...`),
+/// as strings.
+#[derive(Debug, Default)]
+pub struct DicomTableFactory {
+    options: DicomOptions,
+}
+
+impl DicomTableFactory {
+    /// A factory of tables read with `options`, before the ones of each table are applied.
+    pub fn new(options: DicomOptions) -> Self {
+        DicomTableFactory { options }
+    }
+
+    /// The options of the table, from the ones of the factory and of `CREATE EXTERNAL TABLE`.
+    fn table_options(&self, cmd: &CreateExternalTable) -> Result<DicomOptions, DataFusionError> {
+        // Sorted, so `window` is set before `window_output`
+        let mut keys = cmd.options.keys().collect::<Vec<_>>();
+        keys.sort();
+        keys.into_iter().try_fold(self.options.clone(), |options, key| {
+            options.with_option(key.strip_prefix("format.").unwrap_or(key), &cmd.options[key])
+                   .map_err(DataFusionError::Plan)
+        })
+    }
+
+    /// The names of the hive-style directories (`name=value`) the DICOM files under
+    /// `table_path` are in, as far as they are the same for all of them
+    ///
+    /// The files that are not read with `options` (as a `LICENSE` or a `metadata.csv`) do not
+    /// count. They are only opened when their directories would shorten the partitions.
+    async fn infer_partitions(state: &SessionState,
+                              table_path: &ListingTableUrl,
+                              options: &DicomOptions) -> Result<Vec<String>, DataFusionError> {
+        let url = table_path.object_store();
+        let store = StoreRef {
+            store: state.runtime_env().object_store(&url)?,
+            base_url: url.as_str().trim_end_matches('/').to_string(),
+        };
+        let root = location(&store, table_path.prefix().clone()).to_string();
+        let objects = store.store.list(Some(table_path.prefix())).try_collect::<Vec<_>>().await?;
+        let mut result: Option<Vec<String>> = None;
+        for object in objects {
+            let relative_path = object.location
+                                      .as_ref()
+                                      .strip_prefix(table_path.prefix().as_ref())
+                                      .unwrap_or(object.location.as_ref())
+                                      .trim_start_matches('/')
+                                      .to_string();
+            let directories = relative_path.rsplit_once('/').map_or("", |(directories, _)| directories);
+            let names = directories.split('/')
+                                   .map_while(|x| x.split_once('=').map(|(name, _)| name.to_string()))
+                                   .collect::<Vec<_>>();
+            if result.as_ref().is_some_and(|result| names.starts_with(result)) {
+                continue;
+            }
+            let location = location(&store, object.location);
+            if !options.discovery.accepts(&root, &location) {
+                continue;
+            }
+            result = Some(match result {
+                Some(result) => result.into_iter().zip(names).take_while(|(x, y)| x == y).map(|(x, _)| x).collect(),
+                None => names,
+            });
+        }
+        Ok(result.unwrap_or_default())
+    }
+}
+
+#[async_trait]
+impl TableProviderFactory for DicomTableFactory {
+    async fn create(&self, state: &SessionState, cmd: &CreateExternalTable) -> Result<Arc<dyn TableProvider>, DataFusionError> {
+        let options = self.table_options(cmd)?;
+        let table_path = ListingTableUrl::parse(&cmd.location)?;
+        let partition_columns = match cmd.table_partition_cols.is_empty() {
+            true => DicomTableFactory::infer_partitions(state, &table_path, &options).await?,
+            false => cmd.table_partition_cols.clone(),
+        };
+
+        let format = DicomFormat::new(options.clone()).with_root(table_path.as_str());
+        let listing_options = ListingOptions::new(Arc::new(format))
+            .with_file_extension("")
+            .with_collect_stat(false)
+            .with_table_partition_cols(partition_columns.into_iter().map(|x| (x, DataType::Utf8)).collect());
+        let config = ListingTableConfig::new(table_path)
+            .with_listing_options(listing_options)
+            .with_schema(Arc::new(options.schema()));
+        let table = ListingTable::try_new(config)?.with_definition(cmd.definition.clone());
+        Ok(Arc::new(DicomListingTable(table)))
+    }
+}
+
+/// A `ListingTable` of DICOM files, that also lists the files in subdirectories
+///
+/// Listing tables ignore the files in subdirectories (except the partition ones) by default
+/// (`datafusion.execution.listing_table_ignore_subdirectory`), where the series usually are.
+struct DicomListingTable(ListingTable);
+
+#[async_trait]
+impl TableProvider for DicomListingTable {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn schema(&self) -> SchemaRef {
+        self.0.schema()
+    }
+    fn table_type(&self) -> TableType {
+        self.0.table_type()
+    }
+    fn get_table_definition(&self) -> Option<&str> {
+        self.0.get_table_definition()
+    }
+    fn supports_filters_pushdown(&self, filters: &[&Expr]) -> Result<Vec<TableProviderFilterPushDown>, DataFusionError> {
+        self.0.supports_filters_pushdown(filters)
+    }
+    async fn scan(&self,
+                  state: &SessionState,
+                  projection: Option<&Vec<usize>>,
+                  filters: &[Expr],
+                  limit: Option<usize>) -> Result<Arc<dyn ExecutionPlan>, DataFusionError> {
+        let mut state = state.clone();
+        state.config_mut().options_mut().execution.listing_table_ignore_subdirectory = false;
+        self.0.scan(&state, projection, filters, limit).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use arrow::array::{AsArray, RecordBatch};
+    use arrow::datatypes::UInt16Type;
+    use crate::datafusion_reader::DicomTableProvider;
+    use crate::datafusion_udf::dicom_session_context;
+    use super::*;
+
+    /// Two patients with a series of 2 and 1 files, and files that are not DICOM.
+    fn directory() -> tempfile::TempDir {
+        let directory = tempfile::tempdir().unwrap();
+        for (series, files) in [("patient=p1/study=s1/ct", 2), ("patient=p2/study=s2/ct", 1)] {
+            std::fs::create_dir_all(directory.path().join(series)).unwrap();
+            for file in ["1-001.dcm", "1-002.dcm"].into_iter().take(files) {
+                std::fs::copy(Path::new("data/tciaDownload/pat1").join(file), directory.path().join(series).join(file)).unwrap();
+            }
+        }
+        std::fs::write(directory.path().join("LICENSE"), "Not a DICOM file").unwrap();
+        std::fs::write(directory.path().join("patient=p1/metadata.csv"), "patient\np1\n").unwrap();
+        directory
+    }
+
+    /// The result of the last of the `queries`, in a `dicom_session_context` with a `provider`
+    /// table of `directory`.
+    fn sql(directory: &Path, queries: &[String]) -> Result<Vec<RecordBatch>, DataFusionError> {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let context = dicom_session_context();
+            context.register_table("provider", Arc::new(DicomTableProvider::new(directory)))?;
+            let mut result = Vec::new();
+            for query in queries {
+                result = context.sql(query).await?.collect().await?;
+            }
+            Ok(result)
+        })
+    }
+
+    fn strings(batches: &[RecordBatch], column: usize) -> Vec<String> {
+        batches.iter()
+               .flat_map(|x| x.column(column).as_string::<i32>().iter().map(|x| x.unwrap_or("null").to_string()).collect::<Vec<_>>())
+               .collect()
+    }
+
+    #[test]
+    fn inferred_partitions() {
+        let directory = directory();
+        let root = directory.path().display().to_string();
+        let create = format!("CREATE EXTERNAL TABLE scans STORED AS DICOM LOCATION '{}/'", root);
+        let batches = sql(directory.path(), &[create.clone(), "SELECT patient, study, path, frames FROM scans ORDER BY path".to_string()]).unwrap();
+        assert_eq!(strings(&batches, 0), vec!["p1", "p2"]);
+        assert_eq!(strings(&batches, 1), vec!["s1", "s2"]);
+        // The same paths as the ones of a `DicomTableProvider`
+        let paths = vec![format!("{}/patient=p1/study=s1/ct", root), format!("{}/patient=p2/study=s2/ct", root)];
+        assert_eq!(strings(&batches, 2), paths);
+        let provider = sql(directory.path(), &[create.clone(), "SELECT path FROM provider ORDER BY path".to_string()]).unwrap();
+        assert_eq!(strings(&provider, 0), paths);
+        let frames = batches.iter().flat_map(|x| x.column(3).as_primitive::<UInt16Type>().values().to_vec()).collect::<Vec<_>>();
+        assert_eq!(frames, vec![2, 1]);
+
+        let batches = sql(directory.path(), &[create, "SELECT path FROM scans WHERE patient = 'p2'".to_string()]).unwrap();
+        assert_eq!(strings(&batches, 0), paths[1..]);
+    }
+
+    #[test]
+    fn options_and_partitions_of_the_table() {
+        let directory = directory();
+        let root = directory.path().display();
+        // The discovery patterns are relative to the location
+        let create = format!("CREATE EXTERNAL TABLE scans STORED AS DICOM PARTITIONED BY (patient) LOCATION '{}' \
+                              OPTIONS ('granularity' 'instance', 'tags' 'PatientID', 'exclude' 'patient=p1/**/1-002.dcm')", root);
+        let batches = sql(directory.path(), &[create, "SELECT patient, path, patientid FROM scans ORDER BY path".to_string()]).unwrap();
+        assert_eq!(batches[0].schema().fields().iter().map(|x| x.name().as_str()).collect::<Vec<_>>(),
+                   vec!["patient", "path", "patientid"]);
+        assert_eq!(strings(&batches, 0), vec!["p1", "p2"]);
+        assert_eq!(strings(&batches, 1), vec![format!("{}/patient=p1/study=s1/ct/1-001.dcm", root),
+                                              format!("{}/patient=p2/study=s2/ct/1-001.dcm", root)]);
+
+        let create = format!("CREATE EXTERNAL TABLE scans STORED AS DICOM LOCATION '{}' OPTIONS ('order' 'size')", root);
+        assert!(sql(directory.path(), &[create]).unwrap_err().to_string().contains("Unknown series order: size"));
+    }
+
+    #[test]
+    fn partitions_of_the_dicom_files() {
+        let directory = directory();
+        // A DICOM file outside of the partitions
+        std::fs::copy("data/tciaDownload/pat1/1-001.dcm", directory.path().join("1.dcm")).unwrap();
+        let create = format!("CREATE EXTERNAL TABLE scans STORED AS DICOM LOCATION '{}'", directory.path().display());
+        let batches = sql(directory.path(), &[create, "SELECT * FROM scans LIMIT 0".to_string()]).unwrap();
+        assert!(batches.iter().all(|x| x.schema().field_with_name("patient").is_err()));
+    }
+}
@@ -1,143 +1,591 @@
-use std::path::{Path, PathBuf};
+use std::collections::{HashMap, HashSet};
 use std::pin::Pin;
 use std::sync::Arc;
 use async_trait::async_trait;
-use arrow::datatypes::{Schema, Field, DataType};
-use arrow::record_batch::RecordBatch;
+use arrow::array::{new_null_array, Array, ArrayRef, AsArray};
+use arrow::datatypes::Schema;
+use arrow::record_batch::{RecordBatch, RecordBatchOptions};
 use datafusion::datasource::{TableProvider, TableType};
 use datafusion::execution::context::SessionState;
 use datafusion::execution::TaskContext;
+use datafusion::execution::memory_pool::MemoryConsumer;
 use datafusion::physical_plan::{ExecutionPlan, PlanProperties, Partitioning, ExecutionMode,
                                 RecordBatchStream, DisplayAs, DisplayFormatType, project_schema};
 use datafusion::physical_plan::stream::RecordBatchStreamAdapter;
-use datafusion::physical_expr::EquivalenceProperties;
-use datafusion_expr::Expr;
+use datafusion::physical_plan::metrics::{ExecutionPlanMetricsSet, MetricsSet};
+use datafusion::physical_expr::{EquivalenceProperties, PhysicalExpr, PhysicalSortExpr};
+use datafusion::physical_expr::expressions::Column;
+use datafusion::physical_expr::utils::{collect_columns, split_conjunction};
+use arrow::compute::SortOptions;
+use datafusion::common::DFSchema;
+use datafusion_expr::utils::conjunction;
+use datafusion_expr::{Expr, TableProviderFilterPushDown};
 use datafusion::error::DataFusionError;
+use datafusion::common::stats::Precision;
+use datafusion::common::{ColumnStatistics, ScalarValue, Statistics};
+use crate::metrics::ScanMetrics;
 use crate::reader;
+use crate::storage::DicomSource;
 
 type ResultExecute = Result<Pin<Box<dyn RecordBatchStream<Item = Result<RecordBatch,
                                                                         DataFusionError>> + Send>>,
                             DataFusionError>;
 
-#[derive(Debug)]
-struct DicomExecutionPlan {
-    path: PathBuf,
+/// The scan of the series of a source, discovered when planning
+///
+/// The series are discovered once, so the plan knows how many rows it returns (and
+/// `COUNT(*)` is answered without reading the files), and they are not discovered again
+/// when executing it.
+///
+/// The columns of the schema after the ones of the options are partition columns (as the
+/// `patient=...` directories of a `ListingTable`), with a value for each file.
+///
+/// The filters on the columns known before reading the voxels (see `pruning_columns`) skip
+/// the series they are false for when planning, so they are neither counted nor read.
+pub(crate) struct DicomExecutionPlan {
+    source: DicomSource,
+    reader: reader::DicomReader,
+    /// The projected partition columns, with their value for each series.
+    partition_columns: Vec<(String, Vec<ScalarValue>)>,
     properties: PlanProperties,
+    statistics: Statistics,
     limit: Option<usize>,
+    options: reader::DicomOptions,
+    /// The filters the series were pruned with, as displayed by `EXPLAIN`.
+    filters: Vec<String>,
+    /// What the scan reads, from the discovery and the statistics when planning to the
+    /// executions, shown by `EXPLAIN ANALYZE`.
+    scan_metrics: ScanMetrics,
+    metrics: ExecutionPlanMetricsSet,
+}
+
+impl std::fmt::Debug for DicomExecutionPlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "DicomExecutionPlan({:?}, {} series)", self.source, self.reader.len())
+    }
 }
 
 impl DicomExecutionPlan {
-    fn new(path: impl AsRef<Path>,
-           schema: Arc<Schema>,
-           projection: Option<&Vec<usize>>,
-           limit: Option<usize>) -> Self {
+    pub(crate) fn new(source: DicomSource,
+                      schema: Arc<Schema>,
+                      projection: Option<&Vec<usize>>,
+                      limit: Option<usize>,
+                      options: reader::DicomOptions,
+                      partition_values: HashMap<String, Vec<ScalarValue>>,
+                      predicate: Option<&Arc<dyn PhysicalExpr>>) -> Result<Self, DataFusionError> {
 
-        let projected_schema = project_schema(&schema, projection).unwrap();
+        let projected_schema = project_schema(&schema, projection)?;
+        // The series are sorted by the columns of their order, as far as they are projected,
+        // and their slabs (the last column of the order being the path) by frame index
+        let mut order = options.discovery.order.columns().to_vec();
+        if options.slab.is_some() {
+            order.push("frame_index");
+        }
+        let ordering = order.iter()
+                            .map_while(|name| Column::new_with_schema(name, &projected_schema).ok())
+                            .map(|column| PhysicalSortExpr {
+                                expr: Arc::new(column),
+                                options: SortOptions { descending: false, nulls_first: false },
+                            })
+                            .collect::<Vec<_>>();
+        let scan_metrics = ScanMetrics::new();
+        let metrics = ExecutionPlanMetricsSet::new();
+        scan_metrics.register(&metrics, 0);
+        let mut reader = reader::DicomReader::with_discovery_metrics(source.clone(), &options.discovery, scan_metrics.clone())?;
+        let file_columns = options.schema().fields().len();
+        // The filters of the pruning columns and the partition columns
+        let mut known_columns = pruning_columns(&options);
+        known_columns.extend(schema.fields()[file_columns..].iter().map(|x| x.name().clone()));
+        let filters = predicate.map_or(Vec::new(), |predicate| split_conjunction(predicate))
+                               .into_iter()
+                               .filter(|filter| collect_columns(filter).iter().all(|x| known_columns.contains(x.name())))
+                               .collect::<Vec<_>>();
+        if !filters.is_empty() {
+            let values = DicomExecutionPlan::series_values(&reader, &schema, &options, &partition_values, &filters)?;
+            let mut keep = vec![true; reader.len()];
+            for filter in filters.iter() {
+                let result = filter.evaluate(&values)?.into_array(values.num_rows())?;
+                let result = result.as_boolean();
+                keep.iter_mut().enumerate().for_each(|(i, x)| *x &= result.is_valid(i) && result.value(i));
+            }
+            scan_metrics.filter_skipped.add(keep.iter().filter(|x| !**x).count());
+            reader.retain(&keep);
+        }
+        let partition_columns = projected_schema.fields()
+                                                .iter()
+                                                .filter_map(|field| {
+                                                    let index = schema.index_of(field.name()).ok()?.checked_sub(file_columns)?;
+                                                    let values = (0..reader.len()).map(|i| {
+                                                        partition_values[&reader.files(i)[0].to_string()][index].clone()
+                                                    });
+                                                    Some((field.name().clone(), values.collect()))
+                                                })
+                                                .collect::<Vec<_>>();
+        let statistics = DicomExecutionPlan::statistics_of(&reader, &projected_schema, limit, &options, &partition_columns);
         let properties = PlanProperties::new(
-            EquivalenceProperties::new(projected_schema.clone()),
+            EquivalenceProperties::new_with_orderings(projected_schema.clone(), &[ordering]),
             Partitioning::UnknownPartitioning(1),
             ExecutionMode::Bounded,
         );
 
-        DicomExecutionPlan {
-            path: path.as_ref().to_path_buf(),
+        Ok(DicomExecutionPlan {
+            source,
+            reader,
+            partition_columns,
             properties,
+            statistics,
             limit,
+            options,
+            filters: filters.iter().map(|x| x.to_string()).collect(),
+            scan_metrics,
+            metrics,
+        })
+    }
+
+    /// The values of the columns of `filters` for each series of `reader` (the other columns
+    /// of `schema` being null), opening the first file of the series for the tag columns.
+    fn series_values(reader: &reader::DicomReader,
+                     schema: &Schema,
+                     options: &reader::DicomOptions,
+                     partition_values: &HashMap<String, Vec<ScalarValue>>,
+                     filters: &[&Arc<dyn PhysicalExpr>]) -> Result<RecordBatch, DataFusionError> {
+        let columns = filters.iter().flat_map(|x| collect_columns(x)).map(|x| x.name().to_string()).collect::<HashSet<_>>();
+        let images = reader.iter().collect::<Vec<_>>();
+        let tag_columns = options.tag_columns();
+        let tags = tag_columns.iter().filter(|(column, _)| columns.contains(column)).collect::<Vec<_>>();
+        let tag_values = match tags.is_empty() {
+            true => vec![Vec::new(); images.len()],
+            false => images.iter().map(|image| image.tag_values(&tags.iter().map(|(_, tag)| *tag).collect::<Vec<_>>())).collect(),
+        };
+        let file_columns = options.schema().fields().len();
+        let arrays = schema.fields().iter().enumerate().map(|(index, field)| {
+            if !columns.contains(field.name()) {
+                return Ok(new_null_array(field.data_type(), images.len()));
+            }
+            let values = images.iter().enumerate().map(|(i, image)| match field.name().as_str() {
+                _ if index >= file_columns => partition_values[&reader.files(i)[0].to_string()][index - file_columns].clone(),
+                "path" => ScalarValue::from(image.path.as_str()),
+                "modality" => ScalarValue::from(image.modality.as_str()),
+                "transfer_syntax" => ScalarValue::from(image.transfer_syntax.as_str()),
+                "study_date" => ScalarValue::Utf8(image.study_date.clone()),
+                "series_number" => ScalarValue::Int32(image.series_number),
+                "series_instance_uid" => ScalarValue::Utf8(image.series_instance_uid.clone()),
+                "columns" => ScalarValue::UInt16(Some(image.columns as u16)),
+                "rows" => ScalarValue::UInt16(Some(image.rows as u16)),
+                name => ScalarValue::Utf8(tags.iter().position(|(column, _)| column == name).and_then(|x| tag_values[i][x].clone())),
+            });
+            let array: ArrayRef = match images.is_empty() {
+                true => new_null_array(field.data_type(), 0),
+                false => ScalarValue::iter_to_array(values)?,
+            };
+            Ok(arrow::compute::cast(&array, field.data_type())?)
+        }).collect::<Result<Vec<_>, DataFusionError>>()?;
+        let fields = schema.fields().iter().map(|x| x.as_ref().clone().with_nullable(true)).collect::<Vec<_>>();
+        let batch_options = RecordBatchOptions::new().with_row_count(Some(images.len()));
+        Ok(RecordBatch::try_new_with_options(Arc::new(Schema::new(fields)), arrays, &batch_options)?)
+    }
+
+    /// The exact number of rows, and the estimated size of the projected columns
+    ///
+    /// The columns are estimated from the headers of the series, read by the discovery
+    /// (so no file is opened here). The null count of the tag columns is not known, as it
+    /// would require opening the files. With slabs the statistics are estimates, from the
+    /// number of files of each series (before the slice selection and the region).
+    fn statistics_of(reader: &reader::DicomReader,
+                     schema: &Schema,
+                     limit: Option<usize>,
+                     options: &reader::DicomOptions,
+                     partition_columns: &[(String, Vec<ScalarValue>)]) -> Statistics {
+        let series_rows = |i: usize| options.slab.map_or(1, |slab| reader.files(i).len().div_ceil(slab).max(1));
+        // The series read before reaching the limit
+        let mut num_rows = 0;
+        let mut num_series = 0;
+        while num_series < reader.len() && limit.is_none_or(|x| num_rows < x) {
+            num_rows += series_rows(num_series);
+            num_series += 1;
+        }
+        let num_rows = limit.map_or(num_rows, |x| x.min(num_rows));
+        let partition_values = |name: &str| partition_columns.iter().find(|(x, _)| x == name).map(|(_, values)| &values[..num_series]);
+        let header_columns = reader::schema();
+        let mut column_sizes = vec![0; schema.fields().len()];
+        let mut null_counts = schema.fields()
+                                    .iter()
+                                    .map(|field| match partition_values(field.name()) {
+                                        Some(values) => Precision::Exact(values.iter().filter(|x| x.is_null()).count()),
+                                        // The statistics are null when no voxel can be decoded, which is only known reading them
+                                        None if field.name().starts_with("voxel_") => Precision::Absent,
+                                        None if header_columns.field_with_name(field.name()).is_ok() => Precision::Exact(0),
+                                        None => Precision::Absent,
+                                    })
+                                    .collect::<Vec<_>>();
+        if schema.fields().iter().any(|x| x.name() != "path" && partition_values(x.name()).is_none()) {
+            for image in reader.iter().take(num_series) {
+                for (i, field) in schema.fields().iter().enumerate() {
+                    column_sizes[i] += image.estimated_size(field.name(), options);
+                    let is_null = match field.name().as_str() {
+                        "study_date" => image.study_date.is_none(),
+                        "series_number" => image.series_number.is_none(),
+                        "series_instance_uid" => image.series_instance_uid.is_none(),
+                        _ => false,
+                    };
+                    null_counts[i] = null_counts[i].add(&Precision::Exact(is_null as usize));
+                }
+            }
+        } else if let Ok(index) = schema.index_of("path") {
+            column_sizes[index] = (0..num_series).map(|i| reader.path(i).len() * series_rows(i)).sum();
+        }
+
+        let precision = |x: Precision<usize>| if options.slab.is_some() { x.to_inexact() } else { x };
+        Statistics {
+            num_rows: precision(Precision::Exact(num_rows)),
+            total_byte_size: Precision::Inexact(column_sizes.iter().sum()),
+            column_statistics: null_counts.into_iter()
+                                          .map(|x| ColumnStatistics {
+                                              null_count: precision(x),
+                                              ..ColumnStatistics::new_unknown()
+                                          })
+                                          .collect(),
         }
     }
 }
 
+/// The columns of the series known before reading their voxels, that filters can prune
+/// the series with
+///
+/// The header columns are the ones of the first file of each series, read by the discovery,
+/// and the tag columns are read from it. The size of the slices is only known without a
+/// region, and the tags of each row without slices nor slabs (as they are the ones of its
+/// first file).
+pub(crate) fn pruning_columns(options: &reader::DicomOptions) -> HashSet<String> {
+    let mut result = ["path", "modality", "transfer_syntax", "study_date", "series_number", "series_instance_uid"]
+        .into_iter()
+        .map(|x| x.to_string())
+        .collect::<HashSet<_>>();
+    if options.region.is_none() {
+        result.extend(["columns".to_string(), "rows".to_string()]);
+    }
+    if options.slices.is_none() && options.slab.is_none() && options.region.is_none() {
+        result.extend(options.tag_columns().into_iter().map(|(column, _)| column));
+    }
+    result
+}
+
 impl DisplayAs for DicomExecutionPlan {
+    /// The source, the columns read and how, and with `EXPLAIN VERBOSE` the number of series
     fn fmt_as(&self,
-              _t: DisplayFormatType,
+              t: DisplayFormatType,
               f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "DicomExecutionPlan")
+        let schema = self.properties.equivalence_properties().schema();
+        let projection = schema.fields().iter().map(|x| x.name().as_str()).collect::<Vec<_>>();
+        write!(f, "DicomExecutionPlan: root={}, projection=[{}]", self.source, projection.join(", "))?;
+        if !self.filters.is_empty() {
+            write!(f, ", filters=[{}]", self.filters.join(", "))?;
+        }
+        if let Some(limit) = self.limit {
+            write!(f, ", limit={}", limit)?;
+        }
+        write!(f, ", partitions={}", self.properties.output_partitioning().partition_count())?;
+        match &self.options.windowing {
+            Some(windowing) => write!(f, ", voxels={} (window {})", windowing.output, windowing.window)?,
+            None => write!(f, ", voxels=i16 (HU)")?,
+        }
+        if let Some(slab) = self.options.slab {
+            write!(f, ", slab={}", slab)?;
+        }
+        if let DisplayFormatType::Verbose = t {
+            write!(f, ", series={}", self.reader.len())?;
+        }
+        Ok(())
     }
 }
 
 
 impl ExecutionPlan for DicomExecutionPlan {
     fn execute(&self,
-               _partition: usize,
+               partition: usize,
                context: Arc<TaskContext>) -> ResultExecute {
 
-        let columns = self.properties
-                          .equivalence_properties()
-                          .schema()
-                          .fields
-                          .into_iter()
-                          .map(|f| f.name().to_string())
-                          .collect::<Vec<_>>();
+        let schema = self.properties.equivalence_properties().schema().clone();
+        let columns = schema.fields
+                            .into_iter()
+                            .map(|f| f.name().to_string())
+                            .filter(|name| self.partition_columns.iter().all(|(x, _)| x != name))
+                            .collect::<Vec<_>>();
 
         let columns_str = columns.iter()
                                  .map(|c| c.as_str())
                                  .collect::<Vec<_>>();
 
         let batch_size = context.session_config().batch_size();
+        let reservation = MemoryConsumer::new(format!("DicomExecutionPlan[{}]", partition)).register(context.memory_pool());
 
-        let record_batch_streamer = RecordBatchStreamAdapter::new(
-            self.properties.equivalence_properties().schema().clone(),
-            reader::DicomStreamer::new(&self.path)
-                .with_projection(Some(columns_str))
-                .with_limit(self.limit)
-                .with_batch_size(Some(batch_size)),
-        );
-        Ok(Box::pin(record_batch_streamer))
+        let mut streamer = reader::DicomStreamer::new(self.source.clone())
+            .with_projection(Some(columns_str))
+            .with_limit(self.limit)
+            .with_batch_size(Some(batch_size))
+            .with_options(self.options.clone())
+            .with_reader(self.reader.clone())
+            .with_metrics(self.scan_metrics.clone())
+            .with_memory_reservation(reservation);
+
+        // The columns in the order of the projection, with the values of the partition columns
+        // for the series of each row
+        let partition_columns = self.partition_columns.clone();
+        let output_schema = schema.clone();
+        let batches = std::iter::from_fn(move || {
+            streamer.try_next_batch().transpose().map(|batch| {
+                let batch = batch?;
+                let series = streamer.batch_series();
+                let columns = output_schema.fields()
+                                           .iter()
+                                           .map(|field| match partition_columns.iter().find(|(x, _)| x == field.name()) {
+                                               Some((_, values)) => ScalarValue::iter_to_array(series.iter().map(|i| values[*i].clone())),
+                                               None => Ok(batch.column_by_name(field.name()).unwrap().clone()),
+                                           })
+                                           .collect::<Result<Vec<_>, _>>()?;
+                let batch_options = RecordBatchOptions::new().with_row_count(Some(batch.num_rows()));
+                Ok(RecordBatch::try_new_with_options(output_schema.clone(), columns, &batch_options)?)
+            })
+        });
+        Ok(Box::pin(RecordBatchStreamAdapter::new(schema, futures::stream::iter(batches))))
     }
 
     fn with_new_children(self: Arc<Self>, _children: Vec<Arc<dyn ExecutionPlan>>) -> Result<Arc<dyn ExecutionPlan>, DataFusionError> {
         Ok(self)
     }
-    fn children(&self) -> Vec<&Arc<(dyn ExecutionPlan + 'static)>> {
+    fn children(&self) -> Vec<&Arc<dyn ExecutionPlan + 'static>> {
         vec![]
     }
     fn properties(&self) -> &PlanProperties {
         &self.properties
     }
+    fn statistics(&self) -> Result<Statistics, DataFusionError> {
+        Ok(self.statistics.clone())
+    }
+    fn metrics(&self) -> Option<MetricsSet> {
+        Some(self.metrics.clone_inner())
+    }
     fn as_any(&self) -> &(dyn std::any::Any + 'static) {
         self
     }
 }
 
 pub struct DicomTableProvider {
-    path: PathBuf,
+    source: DicomSource,
+    options: reader::DicomOptions,
 }
 
 impl DicomTableProvider {
-    pub fn new(path: impl AsRef<Path>) -> Self {
-        DicomTableProvider { path: path.as_ref().to_path_buf() }
+    /// A table of the series in a directory, or in an object store (see `DicomSource`).
+    pub fn new(source: impl Into<DicomSource>) -> Self {
+        DicomTableProvider {
+            source: source.into(),
+            options: reader::DicomOptions::default(),
+        }
+    }
+
+    pub fn with_options(mut self, options: reader::DicomOptions) -> Self {
+        self.options = options;
+        self
     }
 }
 
 #[async_trait]
 impl TableProvider for DicomTableProvider {
     async fn scan(&self,
-                  _state: &SessionState,
+                  state: &SessionState,
                   projection: Option<&Vec<usize>>,
-                  _filters: &[Expr],
+                  filters: &[Expr],
                   limit: Option<usize>) -> Result<Arc<dyn ExecutionPlan>, DataFusionError> {
-        Ok(Arc::new(DicomExecutionPlan::new(&self.path,
+        let predicate = match conjunction(filters.to_vec()) {
+            Some(filter) => Some(state.create_physical_expr(filter, &DFSchema::try_from(self.schema().as_ref().clone())?)?),
+            None => None,
+        };
+        Ok(Arc::new(DicomExecutionPlan::new(self.source.clone(),
                                             self.schema(),
                                             projection,
-                                            limit)))
+                                            limit,
+                                            self.options.clone(),
+                                            HashMap::new(),
+                                            predicate.as_ref())?))
+    }
+    /// The filters of the pruning columns skip series, and are applied again to the rows.
+    fn supports_filters_pushdown(&self, filters: &[&Expr]) -> Result<Vec<TableProviderFilterPushDown>, DataFusionError> {
+        let columns = pruning_columns(&self.options);
+        filters.iter()
+               .map(|filter| Ok(match filter.to_columns()?.iter().all(|x| columns.contains(&x.name)) {
+                   true => TableProviderFilterPushDown::Inexact,
+                   false => TableProviderFilterPushDown::Unsupported,
+               }))
+               .collect()
     }
     fn table_type(&self) -> TableType {
         TableType::View
     }
     fn schema(&self) -> Arc<Schema> {
-        Arc::new(Schema::new(vec![
-            Field::new("path", DataType::Utf8, false),
-            Field::new("modality", DataType::Dictionary(
-                                        Box::new(DataType::Int16),
-                                        Box::new(DataType::Utf8)),
-                       false),
-            Field::new("columns", DataType::UInt16, false),
-            Field::new("rows", DataType::UInt16, false),
-            Field::new("frames", DataType::UInt16, false),
-            Field::new("voxels", DataType::LargeBinary, false),
-        ]))
+        Arc::new(self.options.schema())
     }
     fn as_any(&self) -> &(dyn std::any::Any + 'static) {
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use arrow::array::AsArray;
+    use arrow::datatypes::{DataType, Field};
+    use dicom::core::{DataElement, PrimitiveValue, VR};
+    use dicom::dictionary_std::tags;
+    use datafusion::physical_plan::collect;
+    use datafusion::prelude::SessionContext;
+    use crate::discovery::{DiscoveryOptions, SeriesOrder};
+    use super::*;
+
+    const FILES: [&str; 2] = ["data/tciaDownload/pat1/1-001.dcm", "data/tciaDownload/pat1/1-002.dcm"];
+
+    /// The file `path` with its StudyDate and SeriesNumber, or without them when `None`.
+    fn dated(path: &str, study_date: Option<&str>, series_number: i32) -> Vec<u8> {
+        let mut dicom_file = dicom::object::open_file(path).unwrap();
+        match study_date {
+            Some(study_date) => {
+                dicom_file.put(DataElement::new(tags::STUDY_DATE, VR::DA, PrimitiveValue::from(study_date)));
+            }
+            None => {
+                dicom_file.remove_element(tags::STUDY_DATE);
+            }
+        }
+        dicom_file.put(DataElement::new(tags::SERIES_NUMBER, VR::IS, PrimitiveValue::from(series_number.to_string())));
+        let mut bytes = Vec::new();
+        dicom_file.write_all(&mut bytes).unwrap();
+        bytes
+    }
+
+    /// Three series: `a` of 2 files and `b` of 1 the same day, and `c` of 1 file without StudyDate.
+    fn source() -> DicomSource {
+        DicomSource::from_buffers([("a/1.dcm", dated(FILES[0], Some("20240102"), 2)),
+                                   ("a/2.dcm", dated(FILES[1], Some("20240102"), 2)),
+                                   ("b/1.dcm", dated(FILES[0], Some("20240102"), 1)),
+                                   ("c/1.dcm", dated(FILES[0], None, 3))])
+    }
+
+    fn plan(columns: &[&str], limit: Option<usize>, options: reader::DicomOptions) -> DicomExecutionPlan {
+        let schema = Arc::new(options.schema());
+        let projection = columns.iter().map(|x| schema.index_of(x).unwrap()).collect::<Vec<_>>();
+        DicomExecutionPlan::new(source(), schema, Some(&projection), limit, options, HashMap::new(), None).unwrap()
+    }
+
+    #[test]
+    fn statistics() {
+        let options = reader::DicomOptions::default();
+        let statistics = plan(&["path", "study_date", "voxel_max"], None, options.clone()).statistics().unwrap();
+        assert_eq!(statistics.num_rows, Precision::Exact(3));
+        let null_counts = statistics.column_statistics.iter().map(|x| x.null_count.clone()).collect::<Vec<_>>();
+        assert_eq!(null_counts, vec![Precision::Exact(0), Precision::Exact(1), Precision::Absent]);
+        assert!(statistics.total_byte_size.get_value().is_some_and(|x| *x > 0));
+
+        // Only the series before the limit
+        let statistics = plan(&["path", "study_date"], Some(2), options.clone()).statistics().unwrap();
+        assert_eq!(statistics.num_rows, Precision::Exact(2));
+        assert_eq!(statistics.column_statistics[1].null_count, Precision::Exact(0));
+
+        // Estimated with slabs, from the number of files of each series
+        let statistics = plan(&["path", "frame_index"], None, options.clone().with_slab(Some(1))).statistics().unwrap();
+        assert_eq!(statistics.num_rows, Precision::Inexact(4));
+        assert_eq!(statistics.column_statistics[0].null_count, Precision::Inexact(0));
+
+        // Partition columns, with a null value for the series `b`
+        let mut fields = options.schema().fields().iter().map(|x| x.as_ref().clone()).collect::<Vec<_>>();
+        fields.push(Field::new("site", DataType::Utf8, true));
+        let schema = Arc::new(Schema::new(fields));
+        let partition_values = [("a/1.dcm", Some("x")), ("a/2.dcm", Some("x")), ("b/1.dcm", None), ("c/1.dcm", Some("y"))]
+            .into_iter()
+            .map(|(file, site)| (file.to_string(), vec![ScalarValue::Utf8(site.map(|x| x.to_string()))]))
+            .collect();
+        let projection = vec![schema.index_of("site").unwrap()];
+        let plan = DicomExecutionPlan::new(source(), schema, Some(&projection), None, options, partition_values, None).unwrap();
+        assert_eq!(plan.statistics().unwrap().column_statistics[0].null_count, Precision::Exact(1));
+    }
+
+    /// The names of the columns of the output ordering of `plan`.
+    fn ordering(plan: &DicomExecutionPlan) -> Vec<String> {
+        plan.properties()
+            .output_ordering()
+            .unwrap_or_default()
+            .iter()
+            .map(|x| x.expr.as_any().downcast_ref::<Column>().unwrap().name().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn ordering_of_the_series() {
+        let options = reader::DicomOptions::default()
+            .with_discovery(DiscoveryOptions::default().with_order(SeriesOrder::StudyDateSeriesNumber));
+        let by_date = plan(&["series_number", "path", "study_date"], None, options.clone());
+        assert_eq!(ordering(&by_date), vec!["study_date", "series_number", "path"]);
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let batches = runtime.block_on(collect(Arc::new(by_date), SessionContext::new().task_ctx())).unwrap();
+        let paths = batches.iter().flat_map(|x| x.column(1).as_string::<i32>().iter().flatten().map(|x| x.to_string())).collect::<Vec<_>>();
+        assert_eq!(paths, vec!["b", "a", "c"]);
+
+        // The order is only known as far as its columns are projected
+        assert_eq!(ordering(&plan(&["path", "series_number"], None, options.clone())), Vec::<String>::new());
+        assert_eq!(ordering(&plan(&["study_date", "path"], None, options.clone())), vec!["study_date"]);
+        assert_eq!(ordering(&plan(&["path"], None, reader::DicomOptions::default())), vec!["path"]);
+        assert_eq!(ordering(&plan(&["path", "frame_index"], None, reader::DicomOptions::default().with_slab(Some(1)))),
+                   vec!["path", "frame_index"]);
+
+        // So sorting by the order does not sort the rows again
+        let context = SessionContext::new();
+        context.register_table("series", Arc::new(DicomTableProvider::new(source()).with_options(options))).unwrap();
+        let explain = runtime.block_on(async {
+            context.sql("EXPLAIN SELECT path FROM series ORDER BY study_date, series_number, path").await?.collect().await
+        }).unwrap();
+        let physical_plan = explain[0].column(1).as_string::<i32>().value(1);
+        assert!(physical_plan.contains("DicomExecutionPlan") && !physical_plan.contains("SortExec"), "{}", physical_plan);
+    }
+
+    /// The result of `query` on the `series` table of `source` read with `options`.
+    fn sql(options: reader::DicomOptions, query: &str) -> Vec<RecordBatch> {
+        let context = SessionContext::new();
+        context.register_table("series", Arc::new(DicomTableProvider::new(source()).with_options(options))).unwrap();
+        tokio::runtime::Runtime::new().unwrap().block_on(async { context.sql(query).await?.collect().await }).unwrap()
+    }
+
+    /// The lines of the physical plan of `query`.
+    fn explain(options: reader::DicomOptions, query: &str) -> String {
+        let batches = sql(options, &format!("EXPLAIN {}", query));
+        batches[0].column(1).as_string::<i32>().value(1).to_string()
+    }
+
+    fn paths(batches: &[RecordBatch]) -> Vec<String> {
+        batches.iter().flat_map(|x| x.column(0).as_string::<i32>().iter().flatten().map(|x| x.to_string())).collect()
+    }
+
+    #[test]
+    fn filters_pushdown() {
+        let options = reader::DicomOptions::default().with_tag("SeriesNumber");
+        let plan = explain(options.clone(), "SELECT path FROM series WHERE study_date = '20240102' AND seriesnumber = '2' AND voxel_max > 0");
+        // Only the filters of the header and tag columns are pushed down, and they are applied again
+        assert!(plan.contains("DicomExecutionPlan: root=4 files in memory, projection=[path, study_date, voxel_max, seriesnumber], \
+                               filters=[study_date@3 = 20240102, seriesnumber@16 = 2]"), "{}", plan);
+        assert!(plan.contains("FilterExec"), "{}", plan);
+
+        assert_eq!(paths(&sql(options.clone(), "SELECT path FROM series WHERE study_date = '20240102' AND seriesnumber = '2'")), vec!["a"]);
+        assert_eq!(paths(&sql(options.clone(), "SELECT path FROM series WHERE study_date IS NULL OR series_number = 1")), vec!["b", "c"]);
+        assert_eq!(paths(&sql(options.clone(), "SELECT path FROM series WHERE voxel_max > 0 AND path <> 'b'")), vec!["a", "c"]);
+        // The series pruned are not counted, nor read
+        let schema = Arc::new(options.schema());
+        let filter = datafusion::prelude::col("series_number").gt(datafusion::prelude::lit(1));
+        let predicate = SessionContext::new().create_physical_expr(filter, &DFSchema::try_from(schema.as_ref().clone()).unwrap()).unwrap();
+        let plan = DicomExecutionPlan::new(source(), schema, None, None, options.clone(), HashMap::new(), Some(&predicate)).unwrap();
+        assert_eq!((0..plan.reader.len()).map(|i| plan.reader.path(i)).collect::<Vec<_>>(), vec!["a", "c"]);
+        assert_eq!(plan.statistics().unwrap().num_rows, Precision::Exact(2));
+        assert_eq!(plan.scan_metrics.filter_skipped.value(), 1);
+        let count = sql(options.clone(), "SELECT COUNT(*) FROM series WHERE series_number > 1");
+        assert_eq!(count[0].column(0).as_primitive::<arrow::datatypes::Int64Type>().value(0), 2);
+
+        // The tags of the rows with slabs are the ones of their first file
+        let plan = explain(options.with_slab(Some(1)), "SELECT path FROM series WHERE seriesnumber = '2' AND modality = 'CT'");
+        assert!(plan.contains("filters=[modality@1 = CT], partitions"), "{}", plan);
+    }
+}
@@ -0,0 +1,485 @@
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use arrow::array::{Array, ArrayRef, AsArray, ListArray};
+use arrow::datatypes::{DataType, Field, Float64Type, Int64Type, UInt64Type};
+use datafusion::common::ScalarValue;
+use datafusion::error::DataFusionError;
+use datafusion_expr::function::{AccumulatorArgs, StateFieldsArgs};
+use datafusion_expr::utils::format_state_name;
+use datafusion_expr::{Accumulator, AggregateUDFImpl, Signature};
+use crate::datafusion_udf::{voxel_signature, VoxelEncoding};
+use crate::statistics::HistogramOptions;
+
+/// The rows of `values` where none of the arguments is null.
+fn rows(values: &[ArrayRef]) -> impl Iterator<Item = usize> + '_ {
+    (0..values[0].len()).filter(|row| values.iter().all(|x| !x.is_null(*row)))
+}
+
+fn u64_list(values: &[u64]) -> ScalarValue {
+    let list = ListArray::from_iter_primitive::<UInt64Type, _, _>(vec![Some(values.iter().map(|x| Some(*x)))]);
+    ScalarValue::List(Arc::new(list))
+}
+
+fn f64_list(values: &[f64]) -> ScalarValue {
+    let list = ListArray::from_iter_primitive::<Float64Type, _, _>(vec![Some(values.iter().map(|x| Some(*x)))]);
+    ScalarValue::List(Arc::new(list))
+}
+
+fn list_type(item: DataType) -> DataType {
+    DataType::List(Arc::new(Field::new("item", item, true)))
+}
+
+/// `dicom_histogram(voxels, lo, hi, bins [, encoding])`: the histogram of the voxels of all the series
+///
+/// The bins are the ones of the `histogram` column (see `HistogramOptions`), and the
+/// encoding the one of the voxel functions (see `dicom_voxel_at`).
+#[derive(Debug)]
+pub struct DicomHistogramUdaf {
+    signature: Signature,
+}
+
+impl DicomHistogramUdaf {
+    pub fn new() -> Self {
+        DicomHistogramUdaf {
+            signature: voxel_signature(vec![DataType::LargeBinary, DataType::Float64, DataType::Float64, DataType::Int64]),
+        }
+    }
+}
+
+impl Default for DicomHistogramUdaf {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AggregateUDFImpl for DicomHistogramUdaf {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn name(&self) -> &str {
+        "dicom_histogram"
+    }
+    fn signature(&self) -> &Signature {
+        &self.signature
+    }
+    fn return_type(&self, _arg_types: &[DataType]) -> Result<DataType, DataFusionError> {
+        Ok(list_type(DataType::UInt64))
+    }
+    fn accumulator(&self, _acc_args: AccumulatorArgs) -> Result<Box<dyn Accumulator>, DataFusionError> {
+        Ok(Box::new(HistogramAccumulator::default()))
+    }
+    fn state_fields(&self, args: StateFieldsArgs) -> Result<Vec<Field>, DataFusionError> {
+        Ok(vec![
+            Field::new(format_state_name(args.name, "lower"), DataType::Float64, true),
+            Field::new(format_state_name(args.name, "upper"), DataType::Float64, true),
+            Field::new(format_state_name(args.name, "counts"), list_type(DataType::UInt64), true),
+        ])
+    }
+}
+
+/// The bins and their counts, once the first voxels give the bins.
+#[derive(Debug, Default)]
+struct HistogramAccumulator {
+    options: Option<HistogramOptions>,
+    counts: Vec<u64>,
+}
+
+impl HistogramAccumulator {
+    /// The bins, checking all the rows ask for the same ones.
+    fn options(&mut self, lower: f64, upper: f64, bins: i64) -> Result<HistogramOptions, DataFusionError> {
+        let options = HistogramOptions::new(lower, upper, bins.max(0) as usize).map_err(DataFusionError::Execution)?;
+        match self.options {
+            Some(x) if x != options => Err(DataFusionError::Execution(format!("Different histograms: {:?} and {:?}", x, options))),
+            Some(x) => Ok(x),
+            None => {
+                self.options = Some(options);
+                self.counts = vec![0; options.bins];
+                Ok(options)
+            }
+        }
+    }
+}
+
+impl Accumulator for HistogramAccumulator {
+    fn update_batch(&mut self, values: &[ArrayRef]) -> Result<(), DataFusionError> {
+        let voxels = values[0].as_binary::<i64>();
+        let float = |index: usize, row: usize| values[index].as_primitive::<Float64Type>().value(row);
+        for row in rows(values) {
+            let encoding = VoxelEncoding::of(values, 4, row)?;
+            let options = self.options(float(1, row), float(2, row), values[3].as_primitive::<Int64Type>().value(row))?;
+            for value in encoding.values(voxels.value(row)) {
+                if let Some(bin) = options.bin(value) {
+                    self.counts[bin] += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn merge_batch(&mut self, states: &[ArrayRef]) -> Result<(), DataFusionError> {
+        let lower = states[0].as_primitive::<Float64Type>();
+        let upper = states[1].as_primitive::<Float64Type>();
+        let counts = states[2].as_list::<i32>();
+        for row in rows(states) {
+            let row_counts = counts.value(row);
+            self.options(lower.value(row), upper.value(row), row_counts.len() as i64)?;
+            for (count, row_count) in self.counts.iter_mut().zip(row_counts.as_primitive::<UInt64Type>().values()) {
+                *count += row_count;
+            }
+        }
+        Ok(())
+    }
+
+    fn state(&mut self) -> Result<Vec<ScalarValue>, DataFusionError> {
+        Ok(vec![
+            ScalarValue::Float64(self.options.map(|x| x.lower)),
+            ScalarValue::Float64(self.options.map(|x| x.upper)),
+            u64_list(&self.counts),
+        ])
+    }
+
+    fn evaluate(&mut self) -> Result<ScalarValue, DataFusionError> {
+        match self.options {
+            Some(_) => Ok(u64_list(&self.counts)),
+            None => ScalarValue::try_from(&list_type(DataType::UInt64)),
+        }
+    }
+
+    fn size(&self) -> usize {
+        std::mem::size_of_val(self) + self.counts.capacity() * std::mem::size_of::<u64>()
+    }
+}
+
+/// `dicom_mean_volume(voxels [, encoding])`: the voxel-wise mean of volumes of the same shape
+///
+/// The mean volume is in little-endian f32 (`dicom_voxel_at(..., 'f32')` reads it).
+#[derive(Debug)]
+pub struct DicomMeanVolumeUdaf {
+    signature: Signature,
+}
+
+impl DicomMeanVolumeUdaf {
+    pub fn new() -> Self {
+        DicomMeanVolumeUdaf {
+            signature: voxel_signature(vec![DataType::LargeBinary]),
+        }
+    }
+}
+
+impl Default for DicomMeanVolumeUdaf {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AggregateUDFImpl for DicomMeanVolumeUdaf {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn name(&self) -> &str {
+        "dicom_mean_volume"
+    }
+    fn signature(&self) -> &Signature {
+        &self.signature
+    }
+    fn return_type(&self, _arg_types: &[DataType]) -> Result<DataType, DataFusionError> {
+        Ok(DataType::LargeBinary)
+    }
+    fn accumulator(&self, _acc_args: AccumulatorArgs) -> Result<Box<dyn Accumulator>, DataFusionError> {
+        Ok(Box::new(MeanVolumeAccumulator::default()))
+    }
+    fn state_fields(&self, args: StateFieldsArgs) -> Result<Vec<Field>, DataFusionError> {
+        Ok(vec![
+            Field::new(format_state_name(args.name, "sums"), list_type(DataType::Float64), true),
+            Field::new(format_state_name(args.name, "count"), DataType::UInt64, true),
+        ])
+    }
+}
+
+/// The voxel-wise sums of the volumes, and their number.
+#[derive(Debug, Default)]
+struct MeanVolumeAccumulator {
+    sums: Vec<f64>,
+    count: u64,
+}
+
+impl MeanVolumeAccumulator {
+    fn add(&mut self, values: impl ExactSizeIterator<Item = f64>, count: u64) -> Result<(), DataFusionError> {
+        if self.count == 0 {
+            self.sums = vec![0.; values.len()];
+        } else if values.len() != self.sums.len() {
+            return Err(DataFusionError::Execution(format!("Volumes of different sizes: {} and {} voxels",
+                                                          self.sums.len(), values.len())));
+        }
+        for (sum, value) in self.sums.iter_mut().zip(values) {
+            *sum += value;
+        }
+        self.count += count;
+        Ok(())
+    }
+}
+
+impl Accumulator for MeanVolumeAccumulator {
+    fn update_batch(&mut self, values: &[ArrayRef]) -> Result<(), DataFusionError> {
+        let voxels = values[0].as_binary::<i64>();
+        for row in rows(values) {
+            let encoding = VoxelEncoding::of(values, 1, row)?;
+            let volume = encoding.values(voxels.value(row)).collect::<Vec<_>>();
+            self.add(volume.into_iter(), 1)?;
+        }
+        Ok(())
+    }
+
+    fn merge_batch(&mut self, states: &[ArrayRef]) -> Result<(), DataFusionError> {
+        let sums = states[0].as_list::<i32>();
+        let counts = states[1].as_primitive::<UInt64Type>();
+        for row in rows(states).filter(|row| counts.value(*row) > 0) {
+            let row_sums = sums.value(row);
+            self.add(row_sums.as_primitive::<Float64Type>().values().iter().copied(), counts.value(row))?;
+        }
+        Ok(())
+    }
+
+    fn state(&mut self) -> Result<Vec<ScalarValue>, DataFusionError> {
+        Ok(vec![f64_list(&self.sums), ScalarValue::UInt64(Some(self.count))])
+    }
+
+    fn evaluate(&mut self) -> Result<ScalarValue, DataFusionError> {
+        if self.count == 0 {
+            return Ok(ScalarValue::LargeBinary(None));
+        }
+        let means = self.sums.iter().map(|x| x / self.count as f64);
+        Ok(ScalarValue::LargeBinary(Some(VoxelEncoding::F32.encode(means))))
+    }
+
+    fn size(&self) -> usize {
+        std::mem::size_of_val(self) + self.sums.capacity() * std::mem::size_of::<f64>()
+    }
+}
+
+/// The values counted by `dicom_quantile` are rounded to 1/`QUANTILE_RESOLUTION`.
+const QUANTILE_RESOLUTION: f64 = 4096.;
+
+/// `dicom_quantile(voxels, q [, encoding])`: the `q` quantile (0 to 1) of the voxels of all the series
+///
+/// The voxels are counted by value, rounded to 1/4096, so the quantiles are exact for the
+/// HU and u8 voxels, and approximate for the f32 ones.
+#[derive(Debug)]
+pub struct DicomQuantileUdaf {
+    signature: Signature,
+}
+
+impl DicomQuantileUdaf {
+    pub fn new() -> Self {
+        DicomQuantileUdaf {
+            signature: voxel_signature(vec![DataType::LargeBinary, DataType::Float64]),
+        }
+    }
+}
+
+impl Default for DicomQuantileUdaf {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AggregateUDFImpl for DicomQuantileUdaf {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn name(&self) -> &str {
+        "dicom_quantile"
+    }
+    fn signature(&self) -> &Signature {
+        &self.signature
+    }
+    fn return_type(&self, _arg_types: &[DataType]) -> Result<DataType, DataFusionError> {
+        Ok(DataType::Float64)
+    }
+    fn accumulator(&self, _acc_args: AccumulatorArgs) -> Result<Box<dyn Accumulator>, DataFusionError> {
+        Ok(Box::new(QuantileAccumulator::default()))
+    }
+    fn state_fields(&self, args: StateFieldsArgs) -> Result<Vec<Field>, DataFusionError> {
+        Ok(vec![
+            Field::new(format_state_name(args.name, "quantile"), DataType::Float64, true),
+            Field::new(format_state_name(args.name, "values"), list_type(DataType::Float64), true),
+            Field::new(format_state_name(args.name, "counts"), list_type(DataType::UInt64), true),
+        ])
+    }
+}
+
+/// The number of voxels of each (rounded) value.
+#[derive(Debug, Default)]
+struct QuantileAccumulator {
+    quantile: Option<f64>,
+    counts: BTreeMap<i64, u64>,
+}
+
+impl QuantileAccumulator {
+    /// Set the quantile, checking all the rows ask for the same one.
+    fn set_quantile(&mut self, quantile: f64) -> Result<(), DataFusionError> {
+        if !(0. ..=1.).contains(&quantile) {
+            return Err(DataFusionError::Execution(format!("Invalid quantile: {}", quantile)));
+        }
+        match self.quantile {
+            Some(x) if x != quantile => Err(DataFusionError::Execution(format!("Different quantiles: {} and {}", x, quantile))),
+            _ => {
+                self.quantile = Some(quantile);
+                Ok(())
+            }
+        }
+    }
+
+    fn add(&mut self, value: f64, count: u64) {
+        *self.counts.entry((value * QUANTILE_RESOLUTION).round() as i64).or_default() += count;
+    }
+}
+
+impl Accumulator for QuantileAccumulator {
+    fn update_batch(&mut self, values: &[ArrayRef]) -> Result<(), DataFusionError> {
+        let voxels = values[0].as_binary::<i64>();
+        let quantiles = values[1].as_primitive::<Float64Type>();
+        for row in rows(values) {
+            self.set_quantile(quantiles.value(row))?;
+            let encoding = VoxelEncoding::of(values, 2, row)?;
+            for value in encoding.values(voxels.value(row)) {
+                self.add(value, 1);
+            }
+        }
+        Ok(())
+    }
+
+    fn merge_batch(&mut self, states: &[ArrayRef]) -> Result<(), DataFusionError> {
+        let quantiles = states[0].as_primitive::<Float64Type>();
+        let values = states[1].as_list::<i32>();
+        let counts = states[2].as_list::<i32>();
+        for row in rows(states) {
+            self.set_quantile(quantiles.value(row))?;
+            let (row_values, row_counts) = (values.value(row), counts.value(row));
+            let row_values = row_values.as_primitive::<Float64Type>().values();
+            for (value, count) in row_values.iter().zip(row_counts.as_primitive::<UInt64Type>().values()) {
+                self.add(*value, *count);
+            }
+        }
+        Ok(())
+    }
+
+    fn state(&mut self) -> Result<Vec<ScalarValue>, DataFusionError> {
+        let values = self.counts.keys().map(|x| *x as f64 / QUANTILE_RESOLUTION).collect::<Vec<_>>();
+        let counts = self.counts.values().copied().collect::<Vec<_>>();
+        Ok(vec![ScalarValue::Float64(self.quantile), f64_list(&values), u64_list(&counts)])
+    }
+
+    /// The smallest value with at least `q` of the voxels lower or equal to it.
+    fn evaluate(&mut self) -> Result<ScalarValue, DataFusionError> {
+        let total = self.counts.values().sum::<u64>();
+        let (Some(quantile), true) = (self.quantile, total > 0) else {
+            return Ok(ScalarValue::Float64(None));
+        };
+        let rank = ((quantile * total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        let value = self.counts.iter().find_map(|(value, count)| {
+            seen += count;
+            (seen >= rank).then_some(*value as f64 / QUANTILE_RESOLUTION)
+        });
+        Ok(ScalarValue::Float64(value))
+    }
+
+    fn size(&self) -> usize {
+        std::mem::size_of_val(self) + self.counts.len() * 2 * std::mem::size_of::<u64>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow::array::{Float64Array, Int64Array, LargeBinaryArray, RecordBatch};
+    use crate::datafusion_udf::dicom_session_context;
+    use super::*;
+
+    const VOLUMES: [[i16; 4]; 2] = [[0, 1, 2, 3], [3, 4, 5, -1]];
+
+    fn bytes(values: &[i16]) -> Vec<u8> {
+        values.iter().flat_map(|x| x.to_le_bytes()).collect()
+    }
+
+    /// The first value of `aggregate` over the `VOLUMES` and a null, as `v`, where `condition` holds.
+    fn aggregate(aggregate: &str, condition: &str) -> Result<ScalarValue, DataFusionError> {
+        let rows = VOLUMES.iter()
+                          .map(|x| format!("(arrow_cast(X'{}', 'LargeBinary'))", bytes(x).iter().map(|x| format!("{:02x}", x)).collect::<String>()))
+                          .chain(["(arrow_cast(NULL, 'LargeBinary'))".to_string()])
+                          .collect::<Vec<_>>();
+        let query = format!("SELECT {} FROM (VALUES {}) AS t(v) WHERE {}", aggregate, rows.join(", "), condition);
+        let batches: Vec<RecordBatch> = tokio::runtime::Runtime::new().unwrap().block_on(async {
+            dicom_session_context().sql(&query).await?.collect().await
+        })?;
+        ScalarValue::try_from_array(batches[0].column(0), 0)
+    }
+
+    fn f32_values(value: ScalarValue) -> Vec<f32> {
+        let ScalarValue::LargeBinary(Some(bytes)) = value else { panic!("Not a volume: {:?}", value) };
+        bytes.chunks(4).map(|x| f32::from_le_bytes(x.try_into().unwrap())).collect()
+    }
+
+    /// The result of `accumulator` fed with each of the `batches` separately, and merged.
+    fn merged<A: Accumulator + Default>(batches: &[Vec<ArrayRef>]) -> ScalarValue {
+        let states = batches.iter()
+                            .map(|batch| {
+                                let mut accumulator = A::default();
+                                accumulator.update_batch(batch).unwrap();
+                                accumulator.state().unwrap()
+                            })
+                            .collect::<Vec<_>>();
+        let states = (0..states[0].len()).map(|i| ScalarValue::iter_to_array(states.iter().map(|x| x[i].clone())).unwrap())
+                                         .collect::<Vec<_>>();
+        let mut accumulator = A::default();
+        accumulator.merge_batch(&states).unwrap();
+        accumulator.evaluate().unwrap()
+    }
+
+    fn voxels(volume: &[i16]) -> ArrayRef {
+        Arc::new(LargeBinaryArray::from_vec(vec![&bytes(volume)]))
+    }
+
+    #[test]
+    fn histogram() {
+        // -1 and 5 are out of the bins, 4 is in the last one
+        assert_eq!(aggregate("dicom_histogram(v, 0.0, 4.0, 2)", "true").unwrap(), u64_list(&[2, 4]));
+        assert_eq!(aggregate("dicom_histogram(v, 0.0, 4.0, 2)", "false").unwrap(), ScalarValue::try_from(&list_type(DataType::UInt64)).unwrap());
+        assert!(aggregate("dicom_histogram(v, 4.0, 0.0, 2)", "true").unwrap_err().to_string().contains("Invalid histogram"));
+        let different_bins = aggregate("dicom_histogram(v, 0.0, 4.0, CASE WHEN v = X'0000010002000300' THEN 2 ELSE 3 END)", "true");
+        assert!(different_bins.unwrap_err().to_string().contains("Different histograms"));
+
+        let batches = VOLUMES.map(|volume| vec![voxels(&volume),
+                                                Arc::new(Float64Array::from(vec![0.])) as ArrayRef,
+                                                Arc::new(Float64Array::from(vec![4.])),
+                                                Arc::new(Int64Array::from(vec![2]))]);
+        assert_eq!(merged::<HistogramAccumulator>(&batches), u64_list(&[2, 4]));
+    }
+
+    #[test]
+    fn mean_volume() {
+        assert_eq!(f32_values(aggregate("dicom_mean_volume(v)", "true").unwrap()), vec![1.5, 2.5, 3.5, 1.]);
+        assert_eq!(aggregate("dicom_mean_volume(v)", "false").unwrap(), ScalarValue::LargeBinary(None));
+        let mean_u8 = aggregate("dicom_mean_volume(v, 'u8')", "true").unwrap();
+        assert_eq!(f32_values(mean_u8).len(), 8);
+        let different_sizes = aggregate("dicom_mean_volume(CASE WHEN v = X'0000010002000300' THEN v ELSE X'00' END)", "true");
+        assert!(different_sizes.unwrap_err().to_string().contains("Volumes of different sizes"));
+
+        let batches = VOLUMES.map(|volume| vec![voxels(&volume)]);
+        assert_eq!(f32_values(merged::<MeanVolumeAccumulator>(&batches)), vec![1.5, 2.5, 3.5, 1.]);
+    }
+
+    #[test]
+    fn quantile() {
+        // Of -1, 0, 1, 2, 3, 3, 4, 5
+        for (q, value) in [(0., -1.), (0.5, 2.), (0.75, 3.), (1., 5.)] {
+            assert_eq!(aggregate(&format!("dicom_quantile(v, {:.2})", q), "true").unwrap(), ScalarValue::Float64(Some(value)));
+        }
+        assert_eq!(aggregate("dicom_quantile(v, 0.5)", "false").unwrap(), ScalarValue::Float64(None));
+        assert!(aggregate("dicom_quantile(v, 1.5)", "true").unwrap_err().to_string().contains("Invalid quantile: 1.5"));
+
+        let batches = VOLUMES.map(|volume| vec![voxels(&volume), Arc::new(Float64Array::from(vec![0.5])) as ArrayRef]);
+        assert_eq!(merged::<QuantileAccumulator>(&batches), ScalarValue::Float64(Some(2.)));
+    }
+}
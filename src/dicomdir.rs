@@ -0,0 +1,111 @@
+use std::path::Path;
+use dicom::dictionary_std::tags;
+use crate::file::{self, FileLocation};
+
+/// The files of each series indexed in the DICOMDIR at the root of `path`, if there is one
+///
+/// Only the IMAGE records are used, grouped by the SERIES record they are under. The records
+/// are expected in the order of the hierarchy (each SERIES followed by its IMAGE records),
+/// as written by all the media creators we know of, and not followed by their offsets.
+/// Referenced files that do not exist are ignored, and the files of each series are sorted
+/// by name.
+pub fn dicomdir_series(path: impl AsRef<Path>) -> Option<Vec<Vec<FileLocation>>> {
+    let root = path.as_ref();
+    let dicomdir = file::open_file(root.join("DICOMDIR")).ok()?;
+    let records = dicomdir.element(tags::DIRECTORY_RECORD_SEQUENCE).ok()?.items()?;
+
+    let mut series: Vec<Vec<FileLocation>> = Vec::new();
+    for record in records {
+        let record_type = record.element(tags::DIRECTORY_RECORD_TYPE)
+                                .ok()
+                                .and_then(|x| x.to_str().ok())
+                                .map(|x| x.trim().to_string());
+        match record_type.as_deref() {
+            Some("SERIES") => series.push(Vec::new()),
+            Some("IMAGE") => {
+                let Some(file_id) = record.element(tags::REFERENCED_FILE_ID)
+                                          .ok()
+                                          .and_then(|x| x.to_multi_str().ok()) else { continue };
+                let file_path = file_id.iter().fold(root.to_path_buf(), |path, component| path.join(component.trim()));
+                if let Some(files) = series.last_mut().filter(|_| file_path.is_file()) {
+                    files.push(FileLocation::Path(file_path));
+                }
+            }
+            _ => {}
+        }
+    }
+    series.retain(|files| !files.is_empty());
+    series.iter_mut().for_each(|files| files.sort());
+    Some(series)
+}
+
+#[cfg(test)]
+mod tests {
+    use dicom::core::{DataElement, PrimitiveValue, VR};
+    use dicom::core::value::DataSetSequence;
+    use dicom::object::{FileMetaTableBuilder, InMemDicomObject};
+    use crate::discovery::DiscoveryOptions;
+    use crate::metrics::ScanMetrics;
+    use crate::reader::DicomReader;
+    use super::*;
+
+    /// A directory record of `record_type`, referencing the file `file_id` if any.
+    fn record(record_type: &str, file_id: Option<&str>) -> InMemDicomObject {
+        let mut record = InMemDicomObject::new_empty();
+        record.put(DataElement::new(tags::DIRECTORY_RECORD_TYPE, VR::CS, PrimitiveValue::from(record_type)));
+        if let Some(file_id) = file_id {
+            record.put(DataElement::new(tags::REFERENCED_FILE_ID, VR::CS,
+                                        PrimitiveValue::Strs(file_id.split('\\').map(|x| x.to_string()).collect())));
+        }
+        record
+    }
+
+    /// A directory of extensionless files, indexed in a DICOMDIR as 2 series of 2 and 1
+    /// files (and an image that does not exist).
+    fn media() -> tempfile::TempDir {
+        let directory = tempfile::tempdir().unwrap();
+        std::fs::create_dir(directory.path().join("IMAGES")).unwrap();
+        for (name, file) in [("IM0", "1-001.dcm"), ("IM1", "1-002.dcm"), ("IM2", "1-001.dcm")] {
+            std::fs::copy(format!("data/tciaDownload/pat1/{}", file), directory.path().join("IMAGES").join(name)).unwrap();
+        }
+        let records = vec![record("PATIENT", None),
+                           record("STUDY", None),
+                           record("SERIES", None),
+                           record("IMAGE", Some("IMAGES\\IM1")),
+                           record("IMAGE", Some("IMAGES\\IM0")),
+                           record("SERIES", None),
+                           record("IMAGE", Some("IMAGES\\IM2")),
+                           record("SERIES", None),
+                           record("IMAGE", Some("IMAGES\\MISSING"))];
+        let mut dicomdir = InMemDicomObject::new_empty();
+        dicomdir.put(DataElement::new(tags::DIRECTORY_RECORD_SEQUENCE, VR::SQ, DataSetSequence::from(records)));
+        let meta = FileMetaTableBuilder::new().transfer_syntax("1.2.840.10008.1.2.1")
+                                              .media_storage_sop_class_uid("1.2.840.10008.1.3.10")
+                                              .media_storage_sop_instance_uid("1.2.3.4");
+        dicomdir.with_meta(meta).unwrap().write_to_file(directory.path().join("DICOMDIR")).unwrap();
+        directory
+    }
+
+    #[test]
+    fn series_of_the_records() {
+        let media = media();
+        let images = media.path().join("IMAGES");
+        let series = dicomdir_series(media.path()).unwrap();
+        assert_eq!(series, vec![vec![FileLocation::Path(images.join("IM0")), FileLocation::Path(images.join("IM1"))],
+                                vec![FileLocation::Path(images.join("IM2"))]]);
+        assert!(dicomdir_series(&images).is_none());
+
+        let frames = DicomReader::new(media.path()).unwrap().into_iter().map(|x| x.frames).collect::<Vec<_>>();
+        assert_eq!(frames, vec![2, 1]);
+    }
+
+    #[test]
+    fn patterns() {
+        let media = media();
+        let metrics = ScanMetrics::new();
+        let discovery = DiscoveryOptions::default().with_exclude("IM2").unwrap();
+        let reader = DicomReader::with_discovery_metrics(media.path(), &discovery, metrics.clone()).unwrap();
+        assert_eq!(reader.into_iter().map(|x| x.frames).collect::<Vec<_>>(), vec![2]);
+        assert_eq!(metrics.discovery_skipped.value(), 1);
+    }
+}
@@ -0,0 +1,190 @@
+use std::path::{Path, PathBuf};
+use crate::file::{self, FileLocation, FileStart};
+
+/// Which files of a directory are read
+///
+/// Files are recognized as DICOM by their content (the preamble followed by `DICM`),
+/// not by their extension. Files without preamble (see `FileStart`) are only read
+/// when `allow_missing_preamble` is set, as their detection is less reliable.
+///
+/// Patterns with a `/` are matched against the path relative to the directory (or object
+/// store prefix) read (`studies.zip!/inner/path` for the files in archives), and patterns
+/// without it against the file name (`*.dcm`, `IM*`, `scouts/**`).
+/// A file is read if it matches any of the `include` patterns (or there are none),
+/// and none of the `exclude` ones.
+///
+/// With a `catalog`, what is found of each file is kept in it, and only the new or
+/// modified files are opened when reading the directory again (see `Catalog`). It is
+/// not used for directories with a DICOMDIR, that already indexes their files.
+///
+/// The series are read in `order`, the same in every scan. With the `Instance` granularity,
+/// each file is read as a series of its own.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DiscoveryOptions {
+    pub include: Vec<glob::Pattern>,
+    pub exclude: Vec<glob::Pattern>,
+    pub allow_missing_preamble: bool,
+    pub catalog: Option<PathBuf>,
+    pub order: SeriesOrder,
+    pub granularity: Granularity,
+}
+
+/// What each row of the table is
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Granularity {
+    /// A series: the files in the same directory (or under the same DICOMDIR SERIES record).
+    #[default]
+    Series,
+    /// A file, with its path in the `path` column.
+    Instance,
+}
+
+impl std::str::FromStr for Granularity {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "series" => Ok(Granularity::Series),
+            "instance" => Ok(Granularity::Instance),
+            _ => Err(format!("Unknown granularity: {}", value)),
+        }
+    }
+}
+
+/// The order the series are read in
+///
+/// Every order is total: series with the same attributes (or without them, that come
+/// last) are ordered by path, then by their first file. The attributes are the ones of
+/// the header of the first file of each series, read when discovering them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SeriesOrder {
+    /// By the `path` column.
+    #[default]
+    Path,
+    /// By StudyDate, then SeriesNumber (the `study_date` and `series_number` columns).
+    StudyDateSeriesNumber,
+    /// By SeriesInstanceUID (the `series_instance_uid` column).
+    Uid,
+}
+
+impl std::str::FromStr for SeriesOrder {
+    type Err = String;
+
+    /// Parse `path`, `study_date` or `uid`.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "path" => Ok(SeriesOrder::Path),
+            "study_date" => Ok(SeriesOrder::StudyDateSeriesNumber),
+            "uid" => Ok(SeriesOrder::Uid),
+            _ => Err(format!("Unknown series order: {}", value)),
+        }
+    }
+}
+
+impl SeriesOrder {
+    /// The columns the series are sorted by, in ascending order with nulls last.
+    pub fn columns(&self) -> &'static [&'static str] {
+        match self {
+            SeriesOrder::Path => &["path"],
+            SeriesOrder::StudyDateSeriesNumber => &["study_date", "series_number", "path"],
+            SeriesOrder::Uid => &["series_instance_uid", "path"],
+        }
+    }
+}
+
+impl DiscoveryOptions {
+    pub fn new() -> Self {
+        DiscoveryOptions::default()
+    }
+
+    /// Also read the files matching `pattern`, or an error if it is not a valid glob pattern.
+    pub fn with_include(mut self, pattern: &str) -> Result<Self, String> {
+        self.include.push(Self::pattern(pattern)?);
+        Ok(self)
+    }
+
+    /// Do not read the files matching `pattern`, or an error if it is not a valid glob pattern.
+    pub fn with_exclude(mut self, pattern: &str) -> Result<Self, String> {
+        self.exclude.push(Self::pattern(pattern)?);
+        Ok(self)
+    }
+
+    pub fn with_allow_missing_preamble(mut self, allow_missing_preamble: bool) -> Self {
+        self.allow_missing_preamble = allow_missing_preamble;
+        self
+    }
+
+    pub fn with_catalog(mut self, catalog: Option<impl AsRef<Path>>) -> Self {
+        self.catalog = catalog.map(|x| x.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn with_order(mut self, order: SeriesOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    pub fn with_granularity(mut self, granularity: Granularity) -> Self {
+        self.granularity = granularity;
+        self
+    }
+
+    fn pattern(pattern: &str) -> Result<glob::Pattern, String> {
+        glob::Pattern::new(pattern).map_err(|error| format!("Invalid pattern {}: {}", pattern, error))
+    }
+
+    /// Whether `location`, in the directory `root`, matches the `include` and `exclude` patterns.
+    ///
+    /// `root` is displayed as the locations are (its path or url).
+    pub fn matches(&self, root: &str, location: &FileLocation) -> bool {
+        let location_display = location.to_string();
+        let relative_path = location_display.strip_prefix(root)
+                                            .unwrap_or(&location_display)
+                                            .trim_start_matches('/');
+        let pattern_matches = |pattern: &glob::Pattern| {
+            let options = glob::MatchOptions { require_literal_separator: true, ..Default::default() };
+            if pattern.as_str().contains('/') {
+                pattern.matches_with(relative_path, options)
+            } else {
+                pattern.matches_with(location.file_name(), options)
+            }
+        };
+        (self.include.is_empty() || self.include.iter().any(pattern_matches))
+            && !self.exclude.iter().any(pattern_matches)
+    }
+
+    /// Whether the file `location`, in the directory `root`, is read.
+    ///
+    /// DICOMDIR files are never read, as they only index the other files.
+    pub fn accepts(&self, root: &str, location: &FileLocation) -> bool {
+        self.accepts_with(root, location, |location| file::sniff(location))
+    }
+
+    /// Same as `accepts`, with how the file starts found by `sniff` (only called if the
+    /// file matches the patterns).
+    pub fn accepts_with(&self, root: &str, location: &FileLocation, sniff: impl FnOnce(&FileLocation) -> Option<FileStart>) -> bool {
+        if location.file_name() == "DICOMDIR" || !self.matches(root, location) {
+            return false;
+        }
+        match sniff(location) {
+            Some(FileStart::Preamble) => true,
+            Some(_) => self.allow_missing_preamble,
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_patterns() {
+        let discovery = DiscoveryOptions::new().with_include("*.dcm").unwrap().with_exclude("scouts/**").unwrap();
+        assert_eq!(discovery.include.len(), 1);
+        assert_eq!(discovery.exclude.len(), 1);
+        assert_eq!(DiscoveryOptions::new().with_include("[a").unwrap_err(),
+                   "Invalid pattern [a: Pattern syntax error near position 0: invalid range pattern");
+        assert!(DiscoveryOptions::new().with_exclude("***").is_err());
+    }
+}
@@ -0,0 +1,515 @@
+use std::cell::Cell;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use dicom::core::Tag;
+use dicom::core::dictionary::DataDictionary;
+use dicom::dictionary_std::{tags, StandardDataDictionary};
+use dicom::encoding::TransferSyntaxIndex;
+use dicom::object::{DefaultDicomObject, FileMetaTable, FileMetaTableBuilder, InMemDicomObject, OpenFileOptions};
+use dicom::object::file::ReadPreamble;
+use dicom::transfer_syntax::TransferSyntaxRegistry;
+use dicom::transfer_syntax::entries::{DEFLATED_EXPLICIT_VR_LITTLE_ENDIAN, EXPLICIT_VR_LITTLE_ENDIAN,
+                                      IMPLICIT_VR_LITTLE_ENDIAN};
+use object_store::path::Path as ObjectPath;
+use crate::archive;
+use crate::storage::{MemoryFile, StoreRef};
+
+pub type OpenResult = Result<DefaultDicomObject, Box<dyn Error + Send + Sync>>;
+
+thread_local! {
+    static BYTES_READ: Cell<u64> = const { Cell::new(0) };
+}
+
+/// The bytes read from the files by the current thread so far
+///
+/// Files are read synchronously, so the bytes an operation reads are the difference
+/// before and after it (see `ScanMetrics`).
+pub fn bytes_read() -> u64 {
+    BYTES_READ.get()
+}
+
+fn count_bytes_read(bytes: usize) {
+    BYTES_READ.set(BYTES_READ.get() + bytes as u64);
+}
+
+/// A reader counting the bytes read from it in `bytes_read`.
+struct CountingReader<R>(R);
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let length = self.0.read(buf)?;
+        count_bytes_read(length);
+        Ok(length)
+    }
+}
+
+/// The bytes read from memory, counted in `bytes_read`.
+fn counted(bytes: Vec<u8>) -> Vec<u8> {
+    count_bytes_read(bytes.len());
+    bytes
+}
+
+/// Where a DICOM file is read from
+///
+/// Displayed as its path, as `archive.zip!/inner/path` for the members of archives,
+/// as its url for objects, and as its name for the files in memory.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum FileLocation {
+    /// A file in the file system.
+    Path(PathBuf),
+    /// A file in a zip, tar or tar.gz archive (see `ArchiveFormat`).
+    ArchiveMember { archive: PathBuf, member: String },
+    /// An object in an object store (see `DicomSource::object_store`).
+    Object { store: StoreRef, path: ObjectPath },
+    /// A file held by the caller (see `DicomSource::from_buffers`).
+    Memory(MemoryFile),
+}
+
+impl FileLocation {
+    /// A reader of the content of the file, from its start.
+    pub fn reader(&self) -> std::io::Result<Box<dyn Read>> {
+        match self {
+            FileLocation::Path(path) => Ok(Box::new(BufReader::new(CountingReader(File::open(path)?)))),
+            FileLocation::ArchiveMember { archive, member } => {
+                Ok(Box::new(std::io::Cursor::new(counted(archive::read_member(archive, member)?))))
+            }
+            FileLocation::Object { store, path } => Ok(Box::new(std::io::Cursor::new(counted(store.read(path, None)?)))),
+            FileLocation::Memory(file) => Ok(Box::new(std::io::Cursor::new(counted(file.read(None)?)))),
+        }
+    }
+
+    /// The first `length` bytes of the file (all of it if it is shorter).
+    pub fn head(&self, length: usize) -> std::io::Result<Vec<u8>> {
+        match self {
+            FileLocation::Object { store, path } => Ok(counted(store.read(path, Some(length))?)),
+            FileLocation::Memory(file) => Ok(counted(file.read(Some(length))?)),
+            _ => {
+                let mut result = Vec::with_capacity(length);
+                self.reader()?.take(length as u64).read_to_end(&mut result)?;
+                Ok(result)
+            }
+        }
+    }
+
+    /// The name of the file, without its directory.
+    pub fn file_name(&self) -> &str {
+        match self {
+            FileLocation::Path(path) => path.file_name().and_then(|x| x.to_str()).unwrap_or_default(),
+            FileLocation::ArchiveMember { member, .. } => member.rsplit('/').next().unwrap(),
+            FileLocation::Object { path, .. } => path.filename().unwrap_or_default(),
+            FileLocation::Memory(file) => file.name.rsplit('/').next().unwrap(),
+        }
+    }
+
+    /// The directory the file is in, displayed as the location of its files (the `path` column).
+    pub fn directory(&self) -> String {
+        match self {
+            FileLocation::Path(path) => path.parent().unwrap().to_str().unwrap().to_string(),
+            FileLocation::ArchiveMember { archive, member } => {
+                format!("{}!/{}", archive.display(), member.rsplit_once('/').map_or("", |(directory, _)| directory))
+            }
+            FileLocation::Object { store, path } => {
+                store.url(&ObjectPath::from(path.as_ref().rsplit_once('/').map_or("", |(directory, _)| directory)))
+            }
+            FileLocation::Memory(file) => file.name.rsplit_once('/').map_or("", |(directory, _)| directory).to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for FileLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FileLocation::Path(path) => write!(f, "{}", path.display()),
+            FileLocation::ArchiveMember { archive, member } => write!(f, "{}!/{}", archive.display(), member),
+            FileLocation::Object { store, path } => write!(f, "{}", store.url(path)),
+            FileLocation::Memory(file) => write!(f, "{}", file.name),
+        }
+    }
+}
+
+impl From<PathBuf> for FileLocation {
+    fn from(path: PathBuf) -> Self {
+        FileLocation::Path(path)
+    }
+}
+
+impl From<&PathBuf> for FileLocation {
+    fn from(path: &PathBuf) -> Self {
+        FileLocation::Path(path.clone())
+    }
+}
+
+impl From<&Path> for FileLocation {
+    fn from(path: &Path) -> Self {
+        FileLocation::Path(path.to_path_buf())
+    }
+}
+
+impl From<&str> for FileLocation {
+    fn from(path: &str) -> Self {
+        FileLocation::Path(PathBuf::from(path))
+    }
+}
+
+impl From<&FileLocation> for FileLocation {
+    fn from(location: &FileLocation) -> Self {
+        location.clone()
+    }
+}
+
+/// The attributes of a file the series columns are read from, without its pixel data
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileHeader {
+    pub modality: String,
+    /// The UID of the transfer syntax, as stored in the file.
+    pub transfer_syntax: String,
+    pub columns: u16,
+    pub rows: u16,
+    pub bits_allocated: u16,
+    pub samples_per_pixel: u16,
+    /// The attributes the series can be ordered by (see `SeriesOrder`), `None` if missing.
+    pub study_date: Option<String>,
+    pub series_number: Option<i32>,
+    pub series_instance_uid: Option<String>,
+}
+
+impl FileHeader {
+    pub fn read(location: impl Into<FileLocation>) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let (dicom_file, transfer_syntax) = open(&location.into(), Some(tags::PIXEL_DATA))?;
+        let value = |tag| -> Result<u16, Box<dyn Error + Send + Sync>> { Ok(dicom_file.element(tag)?.to_int::<u16>()?) };
+        let optional_str = |tag| dicom_file.element(tag)
+                                          .ok()
+                                          .and_then(|x| x.to_str().ok())
+                                          .map(|x| x.trim().to_string())
+                                          .filter(|x| !x.is_empty());
+        Ok(FileHeader {
+            modality: dicom_file.element(tags::MODALITY)?.to_str()?.trim().to_string(),
+            transfer_syntax,
+            columns: value(tags::COLUMNS)?,
+            rows: value(tags::ROWS)?,
+            bits_allocated: value(tags::BITS_ALLOCATED)?,
+            samples_per_pixel: value(tags::SAMPLES_PER_PIXEL)?,
+            study_date: optional_str(tags::STUDY_DATE),
+            series_number: dicom_file.element(tags::SERIES_NUMBER).ok().and_then(|x| x.to_int::<i32>().ok()),
+            series_instance_uid: optional_str(tags::SERIES_INSTANCE_UID),
+        })
+    }
+
+    /// Fails for the images the reader does not support: only 16 bits monochrome images are.
+    pub fn check_supported(&self) -> Result<(), String> {
+        if self.bits_allocated != 16 {
+            return Err(format!("Only 16 bits pixels are supported. Found bits_allocated={}", self.bits_allocated));
+        }
+        if self.samples_per_pixel != 1 {
+            return Err(format!("Only monochrome files are supported. Found samples_per_pixel={}", self.samples_per_pixel));
+        }
+        Ok(())
+    }
+}
+
+/// How a file starts, which tells DICOM files from the rest without relying on their name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileStart {
+    /// The 128 bytes preamble followed by `DICM`, as in the standard.
+    Preamble,
+    /// `DICM` without the preamble before it.
+    Magic,
+    /// The file meta group, without preamble nor `DICM`.
+    MetaGroup,
+    /// The data set, without preamble nor file meta group (as in old ACR-NEMA files).
+    DataSet,
+}
+
+/// How the file starts, or `None` if it does not look like a DICOM file.
+///
+/// Files without preamble are recognized by their first element, that must be of the file
+/// meta group (0002) or of the identifying group (0008), the ones data sets start with.
+pub fn sniff(location: impl Into<FileLocation>) -> Option<FileStart> {
+    file_start(&location.into().head(132).ok()?)
+}
+
+/// How a file starting with `start` (its first 132 bytes) starts, see `sniff`.
+fn file_start(start: &[u8]) -> Option<FileStart> {
+    if start.len() == 132 && &start[128..] == b"DICM" {
+        return Some(FileStart::Preamble);
+    }
+    if start.starts_with(b"DICM") {
+        return Some(FileStart::Magic);
+    }
+    if start.len() < 8 {
+        return None;
+    }
+    let element = u16::from_le_bytes([start[2], start[3]]);
+    match u16::from_le_bytes([start[0], start[1]]) {
+        0x0002 if element <= 0x0102 => Some(FileStart::MetaGroup),
+        0x0008 if element <= 0x00ff => Some(FileStart::DataSet),
+        _ => None,
+    }
+}
+
+/// Open a DICOM file, including the ones the `dicom` crate can not read
+///
+/// Files in Deflated Explicit VR Little Endian are inflated in memory, and returned
+/// as Explicit VR Little Endian, the transfer syntax of their data set once inflated.
+/// Files without preamble (see `FileStart`) are also supported; the ones without
+/// file meta group get one, with the transfer syntax their data set is in.
+pub fn open_file(location: impl Into<FileLocation>) -> OpenResult {
+    Ok(open(&location.into(), None)?.0)
+}
+
+/// Same as `open_file`, but stop reading before the pixel data when possible.
+pub fn open_file_header(location: impl Into<FileLocation>) -> OpenResult {
+    Ok(open(&location.into(), Some(tags::PIXEL_DATA))?.0)
+}
+
+/// The bytes first requested for the header of an object, multiplied until it is all in them
+const OBJECT_HEADER_BYTES: usize = 64 * 1024;
+
+/// Open the file, with the UID of the transfer syntax stored in it
+///
+/// The headers of objects are read with range requests, and not the whole object.
+fn open(location: &FileLocation, read_until: Option<Tag>) -> Result<(DefaultDicomObject, String), Box<dyn Error + Send + Sync>> {
+    if read_until.is_none() || !matches!(location, FileLocation::Object { .. }) {
+        return open_reader(location, location.reader()?, read_until);
+    }
+    let mut length = OBJECT_HEADER_BYTES;
+    loop {
+        let bytes = location.head(length)?;
+        let complete = bytes.len() < length;
+        let mut reader = std::io::Cursor::new(&bytes[..]);
+        match open_reader(location, &mut reader, read_until) {
+            // Stopping before the end of the bytes, the header was read until the pixel data
+            Ok(result) if complete || (reader.position() as usize) < bytes.len() => return Ok(result),
+            Err(error) if complete => return Err(error),
+            _ => length *= 4,
+        }
+    }
+}
+
+/// Open the file read by `reader`, from its start
+fn open_reader(location: &FileLocation,
+               mut reader: impl Read,
+               read_until: Option<Tag>) -> Result<(DefaultDicomObject, String), Box<dyn Error + Send + Sync>> {
+    let mut start = Vec::with_capacity(132);
+    (&mut reader).take(132).read_to_end(&mut start)?;
+    let file_start = file_start(&start).ok_or_else(|| format!("{} is not a DICOM file", location))?;
+    let reader = std::io::Cursor::new(start).chain(reader);
+    if file_start == FileStart::DataSet {
+        let dicom_file = open_without_meta(reader)?;
+        let transfer_syntax = dicom_file.meta().transfer_syntax().to_string();
+        return Ok((dicom_file, transfer_syntax));
+    }
+
+    let (mut meta, meta_bytes, reader) = read_meta(reader, file_start)?;
+    let transfer_syntax = meta.transfer_syntax().to_string();
+    if transfer_syntax == DEFLATED_EXPLICIT_VR_LITTLE_ENDIAN.uid() {
+        let dataset = InMemDicomObject::read_dataset_with_ts(flate2::read::DeflateDecoder::new(reader),
+                                                             &EXPLICIT_VR_LITTLE_ENDIAN.erased())?;
+        meta.set_transfer_syntax(&EXPLICIT_VR_LITTLE_ENDIAN);
+        return Ok((dataset.with_exact_meta(meta), transfer_syntax));
+    }
+
+    // The meta group is read again by the `dicom` crate, that can stop before the pixel data
+    let options = OpenFileOptions::new().read_preamble(ReadPreamble::Never);
+    let reader = std::io::Cursor::new(meta_bytes).chain(reader);
+    let dicom_file = match read_until {
+        Some(tag) => options.read_until(tag).from_reader(reader)?,
+        None => options.from_reader(reader)?,
+    };
+    Ok((dicom_file, transfer_syntax))
+}
+
+/// The value of the attribute `name` of `dicom_file` as a string, `None` if it does not have it
+///
+/// `name` is a keyword of the standard dictionary in any case (`PatientID`, `patientid`),
+/// or a tag as 8 hexadecimal digits (`00100020`). Multiple values are separated by `\`.
+pub fn tag_value(dicom_file: &DefaultDicomObject, name: &str) -> Option<String> {
+    let tag = if name.len() == 8 && name.chars().all(|x| x.is_ascii_hexdigit()) {
+        Tag(u16::from_str_radix(&name[..4], 16).ok()?, u16::from_str_radix(&name[4..], 16).ok()?)
+    } else {
+        // The dictionary only finds keywords in their case, they are searched in the file otherwise
+        let matches = |tag: &Tag| StandardDataDictionary.by_tag(*tag).is_some_and(|x| x.alias.eq_ignore_ascii_case(name));
+        match StandardDataDictionary.by_name(name) {
+            Some(entry) => entry.tag.inner(),
+            None => dicom_file.iter().map(|x| x.header().tag).find(matches)?,
+        }
+    };
+    Some(dicom_file.element(tag).ok()?.to_str().ok()?.trim().to_string())
+}
+
+/// The transfer syntax UID of a file as stored in it, reading only its header.
+///
+/// Differs from the one in the meta of `open_file` for deflated files.
+pub fn read_transfer_syntax(location: impl Into<FileLocation>) -> Result<String, Box<dyn Error + Send + Sync>> {
+    Ok(open(&location.into(), Some(tags::PIXEL_DATA))?.1)
+}
+
+/// A reader keeping a copy of the bytes read from it
+struct RecordingReader<R> {
+    reader: R,
+    recorded: Vec<u8>,
+}
+
+impl<R: Read> Read for RecordingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let length = self.reader.read(buf)?;
+        self.recorded.extend_from_slice(&buf[..length]);
+        Ok(length)
+    }
+}
+
+/// Read the file meta group from the start of the file
+///
+/// Returns it with its bytes (from `DICM`), and the reader positioned at the data set.
+fn read_meta<R: Read>(mut reader: R, start: FileStart) -> Result<(FileMetaTable, Vec<u8>, R), Box<dyn Error + Send + Sync>> {
+    if start == FileStart::Preamble {
+        reader.read_exact(&mut [0; 128])?;
+    }
+    let mut reader = RecordingReader { reader, recorded: Vec::new() };
+    let meta = match start {
+        // `FileMetaTable` always expects the magic before the group
+        FileStart::MetaGroup => {
+            reader.recorded.extend_from_slice(b"DICM");
+            FileMetaTable::from_reader(std::io::Cursor::new(b"DICM").chain(&mut reader))?
+        }
+        _ => FileMetaTable::from_reader(&mut reader)?,
+    };
+    Ok((meta, reader.recorded, reader.reader))
+}
+
+/// Read a file that starts with its data set, in implicit or explicit VR little endian.
+fn open_without_meta(mut reader: impl Read) -> OpenResult {
+    // The VR of the first element, if the data set has explicit VRs
+    let mut first_element = [0; 6];
+    reader.read_exact(&mut first_element)?;
+    let transfer_syntax = if first_element[4..].iter().all(|x| x.is_ascii_uppercase()) {
+        EXPLICIT_VR_LITTLE_ENDIAN.erased()
+    } else {
+        IMPLICIT_VR_LITTLE_ENDIAN.erased()
+    };
+
+    let reader = std::io::Cursor::new(first_element).chain(reader);
+    let dataset = InMemDicomObject::read_dataset_with_ts(reader, &transfer_syntax)?;
+    let uid = |tag| dataset.element(tag)
+                           .ok()
+                           .and_then(|x| x.to_str().ok())
+                           .map(|x| x.trim_end_matches('\0').to_string())
+                           .unwrap_or_default();
+    let meta = FileMetaTableBuilder::new().transfer_syntax(transfer_syntax.uid())
+                                          .media_storage_sop_class_uid(uid(tags::SOP_CLASS_UID))
+                                          .media_storage_sop_instance_uid(uid(tags::SOP_INSTANCE_UID))
+                                          .build()?;
+    Ok(dataset.with_exact_meta(meta))
+}
+
+/// Write a copy of `input` encoded with the transfer syntax `transfer_syntax_uid`
+///
+/// Only the transfer syntaxes that do not need a pixel data codec (native and deflated)
+/// are supported. This is how the fixtures to check the decoding of each transfer
+/// syntax are generated (see `write_fixture`).
+pub fn write_with_transfer_syntax(input: impl Into<FileLocation>,
+                                  transfer_syntax_uid: &str,
+                                  output: impl AsRef<Path>) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let dicom_file = open_file(input)?;
+    let mut meta = dicom_file.meta().clone();
+    let dataset = dicom_file.into_inner();
+
+    let mut writer = BufWriter::new(File::create(output)?);
+    writer.write_all(&[0; 128])?;
+    writer.write_all(b"DICM")?;
+
+    if transfer_syntax_uid == DEFLATED_EXPLICIT_VR_LITTLE_ENDIAN.uid() {
+        meta.set_transfer_syntax(&DEFLATED_EXPLICIT_VR_LITTLE_ENDIAN);
+        meta.write(&mut writer)?;
+        let mut deflater = flate2::write::DeflateEncoder::new(writer, flate2::Compression::default());
+        dataset.write_dataset_with_ts(&mut deflater, &EXPLICIT_VR_LITTLE_ENDIAN.erased())?;
+        deflater.finish()?.flush()?;
+    } else {
+        let transfer_syntax = TransferSyntaxRegistry.get(transfer_syntax_uid)
+                                                    .filter(|x| x.is_codec_free())
+                                                    .ok_or(format!("Can not write transfer syntax {}", transfer_syntax_uid))?;
+        meta.set_transfer_syntax(transfer_syntax);
+        meta.write(&mut writer)?;
+        dataset.write_dataset_with_ts(&mut writer, transfer_syntax)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+/// The transfer syntaxes `write_with_transfer_syntax` can write, with a name for their fixtures
+pub const FIXTURE_TRANSFER_SYNTAXES: [(&str, &str); 4] = [
+    ("1.2.840.10008.1.2", "implicit_little_endian"),
+    ("1.2.840.10008.1.2.1", "explicit_little_endian"),
+    ("1.2.840.10008.1.2.1.99", "deflated_explicit_little_endian"),
+    ("1.2.840.10008.1.2.2", "explicit_big_endian"),
+];
+
+/// Write a copy of the `.dcm` files of the series `input` in `transfer_syntax_uid` to `output`,
+/// and check it is read as the original
+///
+/// Returns whether the copy has the transfer syntax, frames and voxels of the original.
+pub fn write_fixture(input: impl AsRef<Path>,
+                     transfer_syntax_uid: &str,
+                     output: impl AsRef<Path>) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    std::fs::create_dir_all(&output)?;
+    for entry in std::fs::read_dir(&input)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|x| x == "dcm") {
+            write_with_transfer_syntax(&path, transfer_syntax_uid, output.as_ref().join(path.file_name().unwrap()))?;
+        }
+    }
+
+    let series = |path: &Path| -> Result<_, Box<dyn Error + Send + Sync>> {
+        crate::reader::DicomReader::new(path)?.into_iter()
+                                              .next()
+                                              .ok_or(format!("No DICOM series found in {}", path.display()).into())
+    };
+    let (original, fixture) = (series(input.as_ref())?, series(output.as_ref())?);
+    Ok(fixture.transfer_syntax == transfer_syntax_uid
+        && fixture.frames == original.frames
+        && fixture.voxels() == original.voxels())
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow::array::AsArray;
+    use arrow::datatypes::DataType;
+    use crate::reader::DicomStreamer;
+    use super::*;
+
+    const SERIES: &str = "data/tciaDownload/pat1";
+
+    /// Write the series in `transfer_syntax_uid`, and check it is read as the original.
+    fn assert_fixture(transfer_syntax_uid: &str) {
+        let directory = tempfile::tempdir().unwrap();
+        assert!(write_fixture(SERIES, transfer_syntax_uid, directory.path()).unwrap());
+
+        let batch = DicomStreamer::new(directory.path()).with_projection(Some(vec!["transfer_syntax"]))
+                                                        .to_record_batch()
+                                                        .unwrap();
+        let transfer_syntax = arrow::compute::cast(batch.column(0), &DataType::Utf8).unwrap();
+        assert_eq!(transfer_syntax.as_string::<i32>().iter().collect::<Vec<_>>(), vec![Some(transfer_syntax_uid)]);
+    }
+
+    #[test]
+    fn implicit_little_endian() {
+        assert_fixture("1.2.840.10008.1.2");
+    }
+
+    #[test]
+    fn explicit_little_endian() {
+        assert_fixture("1.2.840.10008.1.2.1");
+    }
+
+    #[test]
+    fn deflated_explicit_little_endian() {
+        assert_fixture("1.2.840.10008.1.2.1.99");
+    }
+
+    #[test]
+    fn explicit_big_endian() {
+        assert_fixture("1.2.840.10008.1.2.2");
+    }
+}
@@ -1,9 +1,47 @@
 mod reader;
+mod file;
+mod archive;
+mod storage;
+mod catalog;
+mod dicomdir;
+mod discovery;
+mod metrics;
+mod windowing;
+mod statistics;
+mod thumbnail;
+mod region;
+mod slices;
+mod slabs;
+mod transfer_syntax;
 mod polars_reader;
+mod datafusion_reader;
+mod datafusion_format;
+mod datafusion_udf;
+mod datafusion_udaf;
 #[cfg(feature = "python")]
 mod pyarrow_reader;
 
 #[cfg(feature = "python")]
 pub use pyarrow_reader::{read_dicom_to_pandas, read_dicom_to_polars, dicom_reader};
 
-pub use polars_reader::DicomScanner;
+pub use reader::{DicomImage, DicomReader, DicomStreamer, DicomOptions, schema, DEFAULT_BATCH_BYTES};
+pub use file::{open_file, read_transfer_syntax, sniff, tag_value, write_fixture, write_with_transfer_syntax, FileHeader, FileLocation,
+               FileStart, FIXTURE_TRANSFER_SYNTAXES};
+pub use archive::ArchiveFormat;
+pub use storage::{DicomSource, MemoryFile, ReadSeek, StoreRef};
+pub use discovery::{DiscoveryOptions, Granularity, SeriesOrder};
+pub use catalog::{Catalog, CatalogEntry};
+pub use metrics::ScanMetrics;
+pub use windowing::{Window, WindowOutput, Windowing};
+pub use statistics::HistogramOptions;
+pub use region::Region;
+pub use slices::SliceSelection;
+pub use slabs::reassemble_slabs;
+pub use transfer_syntax::{supported_transfer_syntaxes, undecodable_files, TransferSyntaxSupport, UndecodableFile};
+pub use thumbnail::{ThumbnailFormat, ThumbnailMode, ThumbnailOptions};
+pub use polars_reader::{DicomFrames, DicomScanner, DicomVoxelExpr};
+pub use datafusion_reader::DicomTableProvider;
+pub use datafusion_format::{DicomFormat, DicomTableFactory};
+pub use datafusion_udf::{dicom_session_context, register_udfs, DicomHuMeanUdf, DicomMipUdf, DicomSliceUdf,
+                         DicomThresholdCountUdf, DicomVoxelAtUdf, DicomWindowUdf, ReadDicomFunction};
+pub use datafusion_udaf::{DicomHistogramUdaf, DicomMeanVolumeUdaf, DicomQuantileUdaf};
@@ -7,12 +7,11 @@ use datafusion::dataframe::DataFrame;
 use datafusion::execution::context::SessionContext;
 use datafusion::logical_expr::LogicalPlanBuilder;
 use datafusion::config::{FormatOptions, TableParquetOptions, ParquetOptions};
-use crate::polars_reader::DicomScanner;
-mod reader;
-mod polars_reader;
-mod datafusion_reader;
+use dicom_reader::{DicomScanner, DicomTableProvider, supported_transfer_syntaxes, undecodable_files,
+                   write_fixture, FIXTURE_TRANSFER_SYNTAXES};
 
 fn exec_polars_pipeline(path: impl AsRef<std::path::Path>) {
+    // The scan is read at once, Polars does not stream anonymous scans (see `DicomFrames`)
     let q = LazyFrame::scan_dicom(path).unwrap()
                 .with_streaming(true)
                 .filter(col("modality").eq(lit("CT")))
@@ -44,6 +43,7 @@ fn exec_polars_pipeline(path: impl AsRef<std::path::Path>) {
         data_pagesize_limit: None,
         maintain_order: false,
     };
+    // Fails with an anonymous scan, `DicomFrames::sink_parquet` writes it in bounded memory
     q.sink_parquet("out_dicom.parquet", options).unwrap();
     */
 }
@@ -51,7 +51,7 @@ fn exec_polars_pipeline(path: impl AsRef<std::path::Path>) {
 async fn exec_datafusion_pipeline(path: impl AsRef<std::path::Path>) {
     let config = SessionConfig::new().with_batch_size(5);
     let ctx = SessionContext::new_with_config(config);
-    let dicom_table = datafusion_reader::DicomTableProvider::new(&path);
+    let dicom_table = DicomTableProvider::new(&path);
     ctx.register_table("dicom_table", std::sync::Arc::new(dicom_table))
         .unwrap();
 
@@ -69,8 +69,10 @@ async fn exec_datafusion_pipeline(path: impl AsRef<std::path::Path>) {
         "
     ).await.unwrap();
 
-    let mut parquet_options = ParquetOptions::default();
-    parquet_options.write_batch_size = 5;
+    let parquet_options = ParquetOptions {
+        write_batch_size: 5,
+        ..Default::default()
+    };
     let table_parquet_options = TableParquetOptions {
         global: parquet_options,
         column_specific_options: Default::default(),
@@ -110,9 +112,62 @@ async fn exec_datafusion_pipeline(path: impl AsRef<std::path::Path>) {
     */
 }
 
+/// List the transfer syntaxes this build can decode and, if a directory is given,
+/// the files in it that can not be decoded.
+fn transfer_syntaxes_report(path: Option<&str>) {
+    for transfer_syntax in supported_transfer_syntaxes() {
+        println!("{:<4} {:<26} {}",
+                 if transfer_syntax.supported { "yes" } else { "no" },
+                 transfer_syntax.uid,
+                 transfer_syntax.name);
+    }
+
+    if let Some(path) = path {
+        let undecodable = undecodable_files(path);
+        println!();
+        println!("{} files could not be decoded", undecodable.len());
+        for file in undecodable {
+            println!("{} ({}): {}",
+                     file.path.display(),
+                     file.transfer_syntax.as_deref().unwrap_or("unknown transfer syntax"),
+                     file.error);
+        }
+    }
+}
+
+/// Write a copy of the series in `input` in every transfer syntax of `FIXTURE_TRANSFER_SYNTAXES`
+/// (a directory for each in `output`), with `write_fixture` as the tests do.
+///
+/// Returns whether all the copies were read as the original.
+fn transfer_syntax_fixtures(input: &str, output: &str) -> bool {
+    let mut all_equal = true;
+    for (uid, name) in FIXTURE_TRANSFER_SYNTAXES {
+        let directory = std::path::Path::new(output).join(name);
+        let equal = write_fixture(input, uid, &directory).unwrap();
+        println!("{:<6} {:<32} {}", if equal { "ok" } else { "FAILED" }, name, directory.display());
+        all_equal &= equal;
+    }
+    all_equal
+}
+
 #[tokio::main]
 async fn main() {
+    let args = std::env::args().collect::<Vec<_>>();
+    if args.get(1).map(|x| x.as_str()) == Some("transfer-syntaxes") {
+        transfer_syntaxes_report(args.get(2).map(|x| x.as_str()));
+        return;
+    }
+    if args.get(1).map(|x| x.as_str()) == Some("fixtures") {
+        if args.len() != 4 {
+            panic!("Usage: {} fixtures <series directory> <output directory>", args[0]);
+        }
+        if !transfer_syntax_fixtures(&args[2], &args[3]) {
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let data_dir = "/home/mgarcia/src/dicom_reader/data/manifest-1677266205028";
-    exec_polars_pipeline(&data_dir);
+    exec_polars_pipeline(data_dir);
     exec_datafusion_pipeline(&data_dir).await;
 }
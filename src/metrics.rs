@@ -0,0 +1,68 @@
+use std::time::Instant;
+use datafusion::physical_plan::metrics::{Count, ExecutionPlanMetricsSet, MetricBuilder, MetricValue, Time};
+use crate::file;
+
+/// What a scan does, counted while it reads the series
+///
+/// Clones share the counters, so a clone kept by the caller is a handle to read them
+/// during or after the scan (see `DicomStreamer::with_metrics` and
+/// `DicomScanner::scan_dicom_with_metrics`). DataFusion scans show them in
+/// `EXPLAIN ANALYZE`.
+#[derive(Debug, Clone, Default)]
+pub struct ScanMetrics {
+    /// The files opened, for their header or their pixel data (including the ones opened
+    /// by the discovery, the slice selection and the patient regions, and for the statistics
+    /// of DataFusion plans).
+    pub files_opened: Count,
+    /// The bytes read from the files opened.
+    pub bytes_read: Count,
+    /// The time reading headers without the pixel data (the ones not in the catalog, and tags).
+    pub header_parse_time: Time,
+    /// The time reading the files with their pixel data, and decoding it.
+    pub pixel_decode_time: Time,
+    /// The series (or files, with the `Instance` granularity) left out by the discovery,
+    /// as none of their files matches its patterns or is a DICOM file, or as their first
+    /// file is not an image the reader supports. The series of the partitions pruned by
+    /// DataFusion filters are never listed, so they are not counted.
+    pub discovery_skipped: Count,
+    /// The series left out by the filters pushed down to a DataFusion scan, on their header
+    /// and tag columns, before reading them (see `DicomTableProvider`).
+    pub filter_skipped: Count,
+    /// The files that could not be read, and were skipped.
+    pub errors_skipped: Count,
+    pub output_rows: Count,
+}
+
+impl ScanMetrics {
+    pub fn new() -> Self {
+        ScanMetrics::default()
+    }
+
+    /// Run `open`, counting it as a file opened in `time`, with the bytes it reads.
+    pub(crate) fn open<T>(&self, time: &Time, open: impl FnOnce() -> T) -> T {
+        let bytes_read = file::bytes_read();
+        let start = Instant::now();
+        let result = open();
+        time.add_elapsed(start);
+        self.files_opened.add(1);
+        self.bytes_read.add((file::bytes_read() - bytes_read) as usize);
+        result
+    }
+
+    /// Report the counters as the metrics of `partition` in `metrics`.
+    pub(crate) fn register(&self, metrics: &ExecutionPlanMetricsSet, partition: usize) {
+        let build = |value| MetricBuilder::new(metrics).with_partition(partition).build(value);
+        build(MetricValue::OutputRows(self.output_rows.clone()));
+        for (name, count) in [("files_opened", &self.files_opened),
+                              ("bytes_read", &self.bytes_read),
+                              ("discovery_skipped", &self.discovery_skipped),
+                              ("filter_skipped", &self.filter_skipped),
+                              ("errors_skipped", &self.errors_skipped)] {
+            build(MetricValue::Count { name: name.into(), count: count.clone() });
+        }
+        for (name, time) in [("header_parse_time", &self.header_parse_time),
+                             ("pixel_decode_time", &self.pixel_decode_time)] {
+            build(MetricValue::Time { name: name.into(), time: time.clone() });
+        }
+    }
+}
@@ -6,22 +6,43 @@ use polars::prelude::{AnonymousScan,
                       LazyFrame,
                       DataFrame,
                       Series,
+                      IntoSeries,
+                      BinaryChunked,
+                      Expr,
+                      GetOutput,
+                      DataType,
+                      PolarsError,
                       PolarsResult,
                       Schema,
                       ArrowSchema,
                       ArrowField,
-                      ArrowDataType,
+                      ParquetWriteOptions,
+                      ParquetWriter,
+                      StatisticsOptions,
                       ScanArgsAnonymous};
-use polars_arrow::datatypes::IntegerType::Int16;
+use crate::metrics::ScanMetrics;
 use crate::reader;
+use crate::windowing::{self, Window, WindowOutput};
 
 pub struct DicomScan {
     path: String,
+    options: reader::DicomOptions,
+    metrics: ScanMetrics,
 }
 
 impl DicomScan {
-    pub fn new(path: impl AsRef<std::path::Path>) -> Self {
-        DicomScan { path: path.as_ref().to_str().unwrap().to_string() }
+    pub fn new(path: impl AsRef<std::path::Path>, options: reader::DicomOptions) -> Self {
+        DicomScan {
+            path: path.as_ref().to_str().unwrap().to_string(),
+            options,
+            metrics: ScanMetrics::default(),
+        }
+    }
+
+    /// Count what the scans read in `metrics`.
+    pub fn with_metrics(mut self, metrics: ScanMetrics) -> Self {
+        self.metrics = metrics;
+        self
     }
 }
 
@@ -36,36 +57,115 @@ impl AnonymousScan for DicomScan {
             projection = Some(columns.iter().map(|string| { string.as_str() }).collect());
         }
 
+        // The frame is built at once, in a single batch, none without series
         let record_batch = reader::DicomStreamer::new(&self.path)
+            .with_batch_bytes(None)
             .with_limit(scan_opts.n_rows)
             .with_projection(projection)
-            .to_record_batch()
-            .unwrap();
-        recordbatch_to_polars_dataframe(record_batch)
+            .with_options(self.options.clone())
+            .with_metrics(self.metrics.clone())
+            .try_next_batch()
+            .map_err(|error| PolarsError::ComputeError(error.to_string().into()))?;
+        match record_batch {
+            Some(record_batch) => recordbatch_to_polars_dataframe(record_batch),
+            None => {
+                let frame = DataFrame::empty_with_schema(&polars_schema(&self.options.schema()));
+                match scan_opts.with_columns {
+                    Some(columns) => frame.select(columns.iter()),
+                    None => Ok(frame),
+                }
+            },
+        }
     }
     fn schema(&self, _infer_schema_length: Option<usize>) -> PolarsResult<Arc<Schema>> {
-        let schema = Schema::from(ArrowSchema::from(vec![
-            ArrowField::new("path", ArrowDataType::Utf8, false),
-            ArrowField::new("modality", ArrowDataType::Dictionary(
-                                            Int16,
-                                            Box::new(ArrowDataType::Utf8),
-                                            false), false),
-            ArrowField::new("columns", ArrowDataType::UInt16, false),
-            ArrowField::new("rows", ArrowDataType::UInt16, false),
-            ArrowField::new("frames", ArrowDataType::UInt16, false),
-            ArrowField::new("voxels", ArrowDataType::Binary, false),
-        ]));
-        Ok(Arc::new(schema))
+        Ok(Arc::new(polars_schema(&self.options.schema())))
     }
     fn allows_projection_pushdown(&self) -> bool {
         true
     }
 }
 
+/// The series streamed by a `DicomStreamer` as Polars frames, a frame for each batch
+///
+/// A `scan_dicom` frame is read at once: the `AnonymousScan` of Polars 0.41 returns a
+/// single frame (it has no batched interface), and anonymous scans are not streamed (not
+/// even with `with_streaming(true)`, and `LazyFrame::sink_parquet` fails on them).
+/// Here only a batch is in memory at a time (see `DicomStreamer::with_batch_bytes`), so
+/// sources of any size can be written to Parquet. This is not a Polars source: lazy
+/// operations are applied to each frame (`frame.lazy()`), and not to the whole source.
+pub struct DicomFrames {
+    streamer: reader::DicomStreamer,
+}
+
+impl DicomFrames {
+    /// The frames of the batches of `streamer`, with its projection, limit and options.
+    pub fn new(streamer: reader::DicomStreamer) -> Self {
+        DicomFrames { streamer }
+    }
+
+    /// Write all the frames to the Parquet file `path`, a row group for each batch
+    ///
+    /// Fails if `options` has a `row_group_size`, as the row groups are the batches (sized
+    /// with `DicomStreamer::with_batch_size` and `with_batch_bytes`). The rows are always
+    /// written in order, whatever `maintain_order`. The min and max statistics are not
+    /// written with the binary columns (`voxels`, `thumbnail`), as they would keep a copy
+    /// of them in memory until the end.
+    pub fn sink_parquet(mut self, path: impl AsRef<std::path::Path>, options: ParquetWriteOptions) -> PolarsResult<()> {
+        if options.row_group_size.is_some() {
+            return Err(PolarsError::InvalidOperation(
+                "row_group_size is not supported, a row group is written for each batch of the streamer".into()));
+        }
+        let file = std::fs::File::create(path)?;
+        let first = self.next().transpose()?;
+        let statistics = match first {
+            Some(ref frame) if frame.dtypes().contains(&DataType::Binary) => StatisticsOptions {
+                min_value: false,
+                max_value: false,
+                ..options.statistics
+            },
+            _ => options.statistics,
+        };
+        let writer = ParquetWriter::new(file)
+            .with_compression(options.compression)
+            .with_statistics(statistics)
+            .with_data_page_size(options.data_pagesize_limit);
+        let Some(first) = first else {
+            // Without series, a file with the columns and no rows
+            writer.finish(&mut DataFrame::empty_with_schema(&polars_schema(&self.streamer.schema())))?;
+            return Ok(());
+        };
+        let mut batched = writer.batched(&first.schema())?;
+        batched.write_batch(&first)?;
+        for frame in self {
+            batched.write_batch(&frame?)?;
+        }
+        batched.finish()?;
+        Ok(())
+    }
+}
+
+impl Iterator for DicomFrames {
+    type Item = PolarsResult<DataFrame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.streamer.try_next_batch() {
+            Ok(record_batch) => record_batch.map(recordbatch_to_polars_dataframe),
+            Err(error) => Some(Err(PolarsError::ComputeError(error.to_string().into()))),
+        }
+    }
+}
+
+fn polars_schema(schema: &arrow::datatypes::Schema) -> Schema {
+    Schema::from(ArrowSchema::from(schema.fields()
+                                         .iter()
+                                         .map(|field| ArrowField::from(field.clone()))
+                                         .collect::<Vec<_>>()))
+}
+
 fn recordbatch_to_polars_dataframe(record_batch: RecordBatch) -> PolarsResult<DataFrame> {
     DataFrame::new(record_batch.columns()
                                .iter()
-                               .zip(record_batch.schema().all_fields().iter().map(|field| { field.name().as_str() }))
+                               .zip(record_batch.schema().fields().iter().map(|field| { field.name().as_str() }))
                                .map(|(arc_dyn_array, col_name)| { (arc_dyn_array.to_data(), col_name) })
                                .map(|(array_data, col_name)| { (polars_arrow::array::from_data(&array_data), col_name) })
                                .map(|(box_dyn_array, col_name)| { Series::try_from((col_name, box_dyn_array)).unwrap() })
@@ -73,9 +173,25 @@ fn recordbatch_to_polars_dataframe(record_batch: RecordBatch) -> PolarsResult<Da
 
 }
 
+/// Scans of DICOM sources as Polars lazy frames
+///
+/// The frames are read at once when collected (see `DicomFrames` for sources that do not
+/// fit in memory).
 pub trait DicomScanner {
     fn scan_dicom(path: impl AsRef<std::path::Path>) -> PolarsResult<LazyFrame> {
-        let function = DicomScan::new(path);
+        Self::scan_dicom_with_options(path, reader::DicomOptions::default())
+    }
+    fn scan_dicom_with_options(path: impl AsRef<std::path::Path>,
+                               options: reader::DicomOptions) -> PolarsResult<LazyFrame> {
+        Self::scan_dicom_with_metrics(path, options, ScanMetrics::default())
+    }
+    /// Scan counting what is read in `metrics`, a handle kept by the caller
+    ///
+    /// The counters add up over the times the frame is collected.
+    fn scan_dicom_with_metrics(path: impl AsRef<std::path::Path>,
+                               options: reader::DicomOptions,
+                               metrics: ScanMetrics) -> PolarsResult<LazyFrame> {
+        let function = DicomScan::new(path, options).with_metrics(metrics);
         let args = ScanArgsAnonymous::default();
 
         LazyFrame::anonymous_scan(Arc::new(function), args)
@@ -83,3 +199,72 @@ pub trait DicomScanner {
 }
 
 impl DicomScanner for LazyFrame {}
+
+/// Operations on the `voxels` column of a scanned DICOM frame
+pub trait DicomVoxelExpr {
+    /// Window the voxels, same as reading them with `DicomOptions::with_windowing`.
+    ///
+    /// Only `Window::Custom` (including the presets) can be used, since the files
+    /// are not available anymore after reading: other windows fail here, before the query runs.
+    fn dicom_window(self, window: Window, output: WindowOutput) -> PolarsResult<Expr>;
+}
+
+impl DicomVoxelExpr for Expr {
+    fn dicom_window(self, window: Window, output: WindowOutput) -> PolarsResult<Expr> {
+        let Window::Custom { center, width } = window else {
+            return Err(PolarsError::InvalidOperation(
+                format!("Only explicit windows can be applied to the voxels column, found {}", window).into()));
+        };
+        Ok(self.map(move |series| {
+            let windowed: BinaryChunked = series.binary()?
+                                                .into_iter()
+                                                .map(|voxels| voxels.map(|x| windowing::window_voxel_bytes(x, center, width, output)))
+                                                .collect();
+            Ok(Some(windowed.with_name(series.name()).into_series()))
+        }, GetOutput::from_type(DataType::Binary)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use polars::prelude::col;
+    use crate::windowing::Windowing;
+    use super::*;
+
+    const SERIES: &str = "data/tciaDownload";
+
+    /// The bytes of the `voxels` of the first row of `frame`.
+    fn voxels(frame: &DataFrame) -> Vec<u8> {
+        frame.column("voxels").unwrap().binary().unwrap().get(0).unwrap().to_vec()
+    }
+
+    #[test]
+    fn window() {
+        let windowed = LazyFrame::scan_dicom(SERIES).unwrap()
+                                                    .select([col("voxels").dicom_window(Window::preset("lung").unwrap(), WindowOutput::U8).unwrap()])
+                                                    .collect()
+                                                    .unwrap();
+        let windowing = Windowing::new(Window::preset("lung").unwrap(), WindowOutput::U8);
+        let read = LazyFrame::scan_dicom_with_options(SERIES, reader::DicomOptions::default().with_windowing(Some(windowing))).unwrap()
+                                                                                                                            .select([col("voxels")])
+                                                                                                                            .collect()
+                                                                                                                            .unwrap();
+        assert_eq!(voxels(&windowed).len(), 2 * 512 * 512);
+        assert_eq!(voxels(&windowed), voxels(&read));
+
+        // Only explicit windows, before the query runs
+        assert!(col("voxels").dicom_window(Window::File, WindowOutput::U8).is_err());
+        assert!(col("voxels").dicom_window(Window::LutSequence, WindowOutput::F32).is_err());
+    }
+
+    #[test]
+    fn no_rows() {
+        let frame = LazyFrame::scan_dicom(SERIES).unwrap()
+                                                 .limit(0)
+                                                 .select([col("path"), col("voxels")])
+                                                 .collect()
+                                                 .unwrap();
+        assert_eq!(frame.shape(), (0, 2));
+        assert_eq!(frame.column("voxels").unwrap().dtype(), &DataType::Binary);
+    }
+}
@@ -1,69 +1,319 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::io::{self, Read, Seek};
 use std::sync::Arc;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use futures::Stream;
-use dicom::pixeldata::PixelDecoder;
+use dicom_pixeldata::PixelDecoder;
+use dicom::object::DefaultDicomObject;
 use dicom::dictionary_std::tags;
 use arrow::datatypes::{Schema, Field, DataType, Int16Type};
-use arrow::array::{RecordBatch, ArrayRef, UInt16Builder, StringBuilder, StringDictionaryBuilder, LargeBinaryBuilder};
+use arrow::array::{RecordBatch, RecordBatchOptions, ArrayRef, UInt16Builder, Int32Builder, Float64Builder, StringBuilder, StringDictionaryBuilder,
+                   LargeBinaryBuilder, ListBuilder, UInt64Builder};
 use datafusion::error::DataFusionError;
+use datafusion::execution::memory_pool::MemoryReservation;
+use crate::windowing::{WindowOutput, Windowing};
+use crate::statistics::{HistogramOptions, VoxelStats};
+use crate::thumbnail::{ThumbnailMode, ThumbnailOptions};
+use crate::region::{Region, VoxelRegion};
+use crate::slices::SliceSelection;
+use crate::file::{self, FileHeader, FileLocation};
+use crate::archive;
+use crate::catalog::Catalog;
+use crate::storage::{DicomSource, MemoryFile};
+use crate::dicomdir;
+use crate::discovery::{DiscoveryOptions, Granularity, SeriesOrder};
+use crate::metrics::ScanMetrics;
 
 /// A standard representation of a Dicom image
 ///
 /// This is not standard in the dimensions, but in the bits used to represent the data.
-/// All the voxels are represented in 16 bits HU.
+/// All the voxels are represented in 16 bits HU (the modality LUT is applied), unless
+/// a window is requested, in which case they are windowed and scaled to u8 or f32.
+/// Whatever the transfer syntax of the files (including big endian), the `voxels`
+/// column is always encoded in little endian. The frames of the files that can not be
+/// read, or that are not of the size of the first file, are zeros (and counted in
+/// `errors_skipped`).
+///
+/// With slabs (see `DicomOptions::with_slab`) an image is a part of its series, with
+/// the `frames` from its `frame_index`.
+#[derive(Clone)]
 pub struct DicomImage {
     pub path: String,
     pub modality: String,
+    pub transfer_syntax: String,
+    pub study_date: Option<String>,
+    pub series_number: Option<i32>,
+    pub series_instance_uid: Option<String>,
     pub columns: usize,
     pub rows: usize,
     pub frames: usize,
-    files: Vec<std::path::PathBuf>,
+    /// The index of the first frame of the image in the series (not 0 for the slabs after the first).
+    pub frame_index: usize,
+    files: Vec<FileLocation>,
+    crop: Option<Crop>,
+    /// Where the files opened are counted (see `DicomReader::with_metrics`).
+    metrics: ScanMetrics,
+    /// The index of the series in its reader, the same for all its slabs.
+    series_index: usize,
 }
-impl DicomImage {
-    fn new(files: Vec<impl AsRef<std::path::Path>>) -> Self {
-        let first_file = files[0].as_ref();
-
-        let path = first_file.parent().unwrap().to_str().unwrap();
-        let first_dicom_file = dicom::object::open_file(first_file).unwrap();
-        let pixel_data = first_dicom_file.decode_pixel_data().unwrap();
-        let columns = pixel_data.columns() as usize;
-        let rows = pixel_data.rows() as usize;
-        let frames = files.len();
-
-        let modality = first_dicom_file.element(tags::MODALITY).unwrap().to_str().unwrap();
 
-        if pixel_data.bits_allocated() != 16 {
-            panic!("Only 16 bits pixels are supported. Found bits_allocated={}", pixel_data.bits_allocated());
+/// The columns and rows of each slice that are kept when reading a region
+#[derive(Clone)]
+struct Crop {
+    columns: std::ops::Range<usize>,
+    rows: std::ops::Range<usize>,
+    slice_columns: usize,
+    slice_rows: usize,
+}
+impl DicomImage {
+    /// The series in the files `(name, bytes)`, whatever their names (see `DicomSource::from_buffers`).
+    ///
+    /// Fails if there are no files, or if the first one is not an image the reader supports
+    /// (see `FileHeader::check_supported`).
+    pub fn from_buffers<N: Into<String>, B: Into<Arc<[u8]>>>(files: impl IntoIterator<Item = (N, B)>) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        DicomImage::from_memory(files.into_iter().map(|(name, bytes)| MemoryFile::from_bytes(name, bytes)).collect())
+    }
+    /// The series in the files `(name, reader)`, whatever their names (see `DicomSource::from_readers`).
+    pub fn from_readers<N: Into<String>, R: Read + Seek + Send + 'static>(files: impl IntoIterator<Item = (N, R)>) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        DicomImage::from_memory(files.into_iter().map(|(name, reader)| MemoryFile::from_reader(name, reader)).collect())
+    }
+    fn from_memory(mut files: Vec<MemoryFile>) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        files.sort();
+        let files = files.into_iter().map(FileLocation::Memory).collect::<Vec<_>>();
+        let header = FileHeader::read(files.first().ok_or("No files")?)?;
+        header.check_supported()?;
+        Ok(DicomImage::with_header(files, header))
+    }
+    /// The series in `files`, with the header of its first file (a supported one).
+    fn with_header(files: Vec<FileLocation>, header: FileHeader) -> Self {
+        DicomImage {
+            path: files[0].directory(),
+            modality: header.modality,
+            transfer_syntax: header.transfer_syntax,
+            study_date: header.study_date,
+            series_number: header.series_number,
+            series_instance_uid: header.series_instance_uid,
+            columns: header.columns as usize,
+            rows: header.rows as usize,
+            frames: files.len(),
+            frame_index: 0,
+            files,
+            crop: None,
+            metrics: ScanMetrics::default(),
+            series_index: 0,
         }
-        if pixel_data.samples_per_pixel() != 1 {
-            panic!("Only monochrome files are supported. Found samples_per_pixel={}", pixel_data.samples_per_pixel());
+    }
+    /// Restrict the image to `region`: only its frames are decoded, and only its voxels returned.
+    ///
+    /// The `columns`, `rows` and `frames` of the image become the ones of the region.
+    /// A series where the region can not be resolved (a `Region::Patient` without the
+    /// geometry of its slices) is counted in `errors_skipped`, and left without frames.
+    pub fn with_region(mut self, region: &Region) -> Self {
+        let resolved = match region.resolve(&self.files, self.columns, self.rows, &self.metrics) {
+            Ok(resolved) => resolved,
+            Err(_) => {
+                self.metrics.errors_skipped.add(1);
+                VoxelRegion { columns: 0..self.columns, rows: 0..self.rows, files: Vec::new() }
+            }
+        };
+        self.crop = Some(Crop {
+            columns: resolved.columns.clone(),
+            rows: resolved.rows.clone(),
+            slice_columns: self.columns,
+            slice_rows: self.rows,
+        });
+        self.columns = resolved.columns.len();
+        self.rows = resolved.rows.len();
+        self.frames = resolved.files.len();
+        self.files = resolved.files;
+        self
+    }
+    /// Keep only the slices in `selection`, the others are never decoded.
+    ///
+    /// `frames` becomes the number of selected slices.
+    pub fn with_slices(mut self, selection: &SliceSelection) -> Self {
+        self.files = selection.select(self.files, &self.metrics);
+        self.frames = self.files.len();
+        self
+    }
+    /// The image split in slabs of `slab` frames (the last one can be smaller), in order.
+    ///
+    /// An image without frames is a single slab.
+    pub fn slabs(mut self, slab: usize) -> Vec<DicomImage> {
+        let slab = slab.max(1);
+        if self.files.is_empty() {
+            return vec![self];
         }
+        let files = std::mem::take(&mut self.files);
+        files.chunks(slab)
+             .enumerate()
+             .map(|(i, files)| DicomImage {
+                 frames: files.len(),
+                 frame_index: self.frame_index + i * slab,
+                 files: files.to_vec(),
+                 ..self.clone()
+             })
+             .collect()
+    }
+    /// Decode the voxels of each file in HU, and pass them to `f` with the file they come from.
+    ///
+    /// The files that can not be read or decoded, or with slices of another size than the
+    /// first file of the series, are counted in `errors_skipped`, and passed to `f` as `None`.
+    fn decode_files(&self, files: &[FileLocation], mut f: impl FnMut(Option<(&DefaultDicomObject, Vec<f32>)>)) {
+        let options = dicom_pixeldata::ConvertOptions::new()
+            .with_modality_lut(dicom_pixeldata::ModalityLutOption::Default)
+            .with_voi_lut(dicom_pixeldata::VoiLutOption::Identity)
+            .with_bit_depth(dicom_pixeldata::BitDepthOption::Auto);
+        let (slice_columns, slice_rows) = self.crop.as_ref().map_or((self.columns, self.rows), |x| (x.slice_columns, x.slice_rows));
+        let has_slice_size = |dicom_file: &DefaultDicomObject, values: &[f32]| {
+            let dimension = |tag| dicom_file.element(tag).ok().and_then(|x| x.to_int::<usize>().ok());
+            dimension(tags::COLUMNS) == Some(slice_columns) && dimension(tags::ROWS) == Some(slice_rows)
+                && values.len() == slice_columns * slice_rows
+        };
 
-        DicomImage {
-            path: path.to_string(),
-            modality: modality.to_string(),
-            columns,
-            rows,
-            frames,
-            files: files.iter().map(|x| x.as_ref().to_path_buf()).collect(),
+        for current_file in files.iter() {
+            // The transfer syntax or the pixel encoding of a file can be unsupported
+            let decoded = self.metrics.open(&self.metrics.pixel_decode_time, || {
+                let dicom_file = file::open_file(current_file).ok()?;
+                let values = dicom_file.decode_pixel_data().ok()?.to_vec_with_options(&options).ok()?;
+                Some((dicom_file, values))
+            });
+            let decoded = decoded.filter(|(dicom_file, values)| has_slice_size(dicom_file, values));
+            let Some((dicom_file, values)) = decoded else {
+                self.metrics.errors_skipped.add(1);
+                f(None);
+                continue;
+            };
+            match self.crop {
+                Some(ref crop) => f(Some((&dicom_file, crop.rows.clone()
+                                                          .flat_map(|row| &values[row * crop.slice_columns + crop.columns.start
+                                                                                  ..row * crop.slice_columns + crop.columns.end])
+                                                          .copied()
+                                                          .collect()))),
+                None => f(Some((&dicom_file, values))),
+            }
         }
     }
-    fn voxels(&self) -> Vec<i16> {
+    /// The voxels in HU, the ones of the files that can not be read are zeros.
+    pub fn voxels(&self) -> Vec<i16> {
         let mut result = Vec::with_capacity(self.columns * self.rows * self.frames);
+        // The modality LUT can not be built for i16 when the rescaled range
+        // of the 16 bits exceeds it, so we convert from f32 (saturating)
+        self.decode_files(&self.files, |decoded| match decoded {
+            Some((_, values)) => result.extend(values.into_iter().map(|x| x.round() as i16)),
+            None => result.resize(result.len() + self.columns * self.rows, 0),
+        });
+        result
+    }
+    /// The voxels as stored in the `voxels` column, updating `stats` in the same pass.
+    ///
+    /// If `keep_voxels` is false the voxels are only decoded to compute `stats`,
+    /// and an empty vector is returned.
+    fn voxel_bytes(&self,
+                   windowing: Option<&Windowing>,
+                   keep_voxels: bool,
+                   mut stats: Option<&mut VoxelStats>) -> Vec<u8> {
+        let bytes_per_voxel = windowing.map_or(2, |x| x.output.bytes_per_voxel());
+        let mut result = Vec::with_capacity(if keep_voxels { self.columns * self.rows * self.frames * bytes_per_voxel } else { 0 });
 
-        let options = dicom::pixeldata::ConvertOptions::new()
-            .with_modality_lut(dicom::pixeldata::ModalityLutOption::None)
-            .with_voi_lut(dicom::pixeldata::VoiLutOption::Default)
-            .with_bit_depth(dicom::pixeldata::BitDepthOption::Auto);
+        self.decode_files(&self.files, |decoded| {
+            // The frames of the files that can not be read, or without the window requested,
+            // are zeros (the bottom of the window), so that the following frames keep their
+            // place in the voxels
+            let mut skip = || if keep_voxels {
+                result.resize(result.len() + self.columns * self.rows * bytes_per_voxel, 0);
+            };
+            let Some((dicom_file, values)) = decoded else {
+                return skip();
+            };
+            let transform = match windowing.filter(|_| keep_voxels) {
+                Some(windowing) => match windowing.window.transform_for(dicom_file) {
+                    Ok(transform) => Some((transform, windowing.output)),
+                    Err(_) => {
+                        self.metrics.errors_skipped.add(1);
+                        return skip();
+                    }
+                },
+                None => None,
+            };
+            if let Some(ref mut stats) = stats {
+                values.iter().for_each(|x| stats.update(*x as f64));
+            }
+            if keep_voxels {
+                match transform {
+                    Some((transform, output)) => result.extend(transform.encode(values.into_iter().map(|x| x as f64), output)),
+                    None => result.extend(values.into_iter().flat_map(|x| (x.round() as i16).to_le_bytes())),
+                }
+            }
+        });
+        result
+    }
+    /// The thumbnail of the series, encoded as configured in `options`.
+    fn thumbnail(&self, options: &ThumbnailOptions) -> Vec<u8> {
+        if self.files.is_empty() {
+            return Vec::new();
+        }
+        let files = match options.mode {
+            ThumbnailMode::MiddleSlice => &self.files[self.files.len() / 2..self.files.len() / 2 + 1],
+            ThumbnailMode::MaximumIntensityProjection => &self.files[..],
+        };
 
-        for current_file in self.files.iter() {
-            if let Ok(dicom_file) = dicom::object::open_file(current_file) {
-                result.extend_from_slice(&dicom_file.decode_pixel_data().unwrap().to_vec_with_options(&options).unwrap());
+        let mut image: Vec<f32> = Vec::new();
+        let mut transform = None;
+        self.decode_files(files, |decoded| {
+            let Some((dicom_file, values)) = decoded else {
+                return;
+            };
+            if image.is_empty() {
+                image = values;
+                // Without the window requested, the thumbnail is not windowed
+                transform = options.window.as_ref().and_then(|x| {
+                    x.transform_for(dicom_file).inspect_err(|_| self.metrics.errors_skipped.add(1)).ok()
+                });
+            } else {
+                image.iter_mut().zip(values).for_each(|(x, y)| *x = x.max(y));
+            }
+        });
+        options.render(&image, self.columns, self.rows, transform.as_ref())
+    }
+}
+impl DicomImage {
+    /// The values of the attributes `tags` of the first file of the series, opening its header.
+    ///
+    /// The values are null when no file is left by the slice selection or region.
+    pub fn tag_values(&self, tags: &[&str]) -> Vec<Option<String>> {
+        let Some(first) = self.files.first() else {
+            return vec![None; tags.len()];
+        };
+        match self.metrics.open(&self.metrics.header_parse_time, || file::open_file_header(first)) {
+            Ok(dicom_file) => tags.iter().map(|x| file::tag_value(&dicom_file, x)).collect(),
+            Err(_) => {
+                self.metrics.errors_skipped.add(1);
+                vec![None; tags.len()]
             }
         }
-        result
+    }
+    /// An estimate of the bytes of `column` in a row for this image, without decoding it
+    ///
+    /// The region and slice selection are not taken into account, and thumbnails are
+    /// counted as raw pixels (PNG ones being smaller).
+    pub fn estimated_size(&self, column: &str, options: &DicomOptions) -> usize {
+        let optional_len = |value: &Option<String>| value.as_ref().map_or(0, |x| x.len());
+        match column {
+            "path" => self.path.len(),
+            // Only the key, the values are shared by the rows
+            "modality" | "transfer_syntax" | "columns" | "rows" | "frames" | "frame_index" => 2,
+            "study_date" => optional_len(&self.study_date),
+            "series_number" => 4,
+            "series_instance_uid" => optional_len(&self.series_instance_uid),
+            "voxels" => self.columns * self.rows * self.frames * options.windowing.as_ref().map_or(2, |x| x.output.bytes_per_voxel()),
+            "voxel_min" | "voxel_max" | "voxel_mean" | "voxel_std" => 8,
+            "histogram" => options.histogram.bins * 8,
+            "thumbnail" => options.thumbnail.size * options.thumbnail.size,
+            _ => 0,
+        }
     }
 }
 impl std::fmt::Debug for DicomImage {
@@ -72,24 +322,414 @@ impl std::fmt::Debug for DicomImage {
     }
 }
 
+/// The schema of all the columns the reader can produce
+///
+/// The columns computed from the voxels (`voxels`, `voxel_*` and `histogram`) are only
+/// computed when projected, and the voxels are decoded once for all of them.
+/// The `thumbnail` is computed separately, from the slices it needs.
+/// The `voxels` are little-endian i16 (or u8 / little-endian f32 when windowed).
+/// `study_date` (as stored, YYYYMMDD), `series_number` and `series_instance_uid` are the
+/// ones of the first file of the series, and null when it does not have them.
+/// The `voxel_*` statistics are null when no voxel is decoded.
+pub fn schema() -> Schema {
+    Schema::new(vec![
+        Field::new("path", DataType::Utf8, false),
+        Field::new("modality", DataType::Dictionary(
+                                    Box::new(DataType::Int16),
+                                    Box::new(DataType::Utf8)),
+                   false),
+        Field::new("transfer_syntax", DataType::Dictionary(
+                                           Box::new(DataType::Int16),
+                                           Box::new(DataType::Utf8)),
+                   false),
+        Field::new("study_date", DataType::Utf8, true),
+        Field::new("series_number", DataType::Int32, true),
+        Field::new("series_instance_uid", DataType::Utf8, true),
+        Field::new("columns", DataType::UInt16, false),
+        Field::new("rows", DataType::UInt16, false),
+        Field::new("frames", DataType::UInt16, false),
+        Field::new("voxels", DataType::LargeBinary, false),
+        Field::new("voxel_min", DataType::Float64, true),
+        Field::new("voxel_max", DataType::Float64, true),
+        Field::new("voxel_mean", DataType::Float64, true),
+        Field::new("voxel_std", DataType::Float64, true),
+        Field::new("histogram", DataType::List(Arc::new(Field::new("item", DataType::UInt64, true))), false),
+        Field::new("thumbnail", DataType::LargeBinary, false),
+    ])
+}
+
+/// Options that change how the images are read, shared by the Polars and DataFusion readers
+#[derive(Debug, Clone, Default)]
+pub struct DicomOptions {
+    pub windowing: Option<Windowing>,
+    pub histogram: HistogramOptions,
+    pub thumbnail: ThumbnailOptions,
+    pub region: Option<Region>,
+    pub slices: Option<SliceSelection>,
+    pub discovery: DiscoveryOptions,
+    /// Attributes read as columns (see `with_tag`).
+    pub tags: Vec<String>,
+    /// The frames of each row, when the series are split in slabs (see `with_slab`).
+    pub slab: Option<usize>,
+}
+
+impl DicomOptions {
+    pub fn new() -> Self {
+        DicomOptions::default()
+    }
+
+    pub fn with_windowing(mut self, windowing: Option<Windowing>) -> Self {
+        self.windowing = windowing;
+        self
+    }
+
+    pub fn with_histogram(mut self, histogram: HistogramOptions) -> Self {
+        self.histogram = histogram;
+        self
+    }
+
+    pub fn with_thumbnail(mut self, thumbnail: ThumbnailOptions) -> Self {
+        self.thumbnail = thumbnail;
+        self
+    }
+
+    /// Frames in the region are counted after the slice selection is applied.
+    pub fn with_region(mut self, region: Option<Region>) -> Self {
+        self.region = region;
+        self
+    }
+
+    pub fn with_slices(mut self, slices: Option<SliceSelection>) -> Self {
+        self.slices = slices;
+        self
+    }
+
+    pub fn with_discovery(mut self, discovery: DiscoveryOptions) -> Self {
+        self.discovery = discovery;
+        self
+    }
+
+    /// Split each series in rows of `slab` frames (1 for a row per slice), with a `frame_index` column
+    ///
+    /// The series are split after the slice selection and the region, and only one slab is
+    /// held in memory at a time, so volumes of any size are read in bounded memory. The
+    /// columns computed from the voxels (statistics, histogram, thumbnail) are the ones of
+    /// each slab. `reassemble_slabs` joins the slabs of each series back.
+    pub fn with_slab(mut self, slab: Option<usize>) -> Self {
+        self.slab = slab.map(|x| x.max(1));
+        self
+    }
+
+    /// Read the attribute `tag` of the first file of each series as a string column
+    ///
+    /// The tag is a keyword in any case or 8 hexadecimal digits (see `tag_value`),
+    /// and the column is named after it in lowercase (`PatientID` is `patientid`). Tags
+    /// named as another column (as `Modality`) are not added.
+    pub fn with_tag(mut self, tag: &str) -> Self {
+        self.tags.push(tag.to_string());
+        self
+    }
+
+    /// Set the option `name` from its value as a string, as in SQL
+    ///
+    /// The options are `tags` (separated by commas), `granularity`, `order`, `window`,
+    /// `window_output` (after `window`), `region`, `slices`, `slab`, `include` and `exclude`, with the
+    /// values parsed as their types are (`granularity=instance`, `window=lung`, `slices=every=2`).
+    pub fn with_option(self, name: &str, value: &str) -> Result<Self, String> {
+        let discovery = self.discovery.clone();
+        Ok(match name {
+            "tags" => value.split(',')
+                           .map(|x| x.trim())
+                           .filter(|x| !x.is_empty())
+                           .fold(self, |options, tag| options.with_tag(tag)),
+            "granularity" => self.with_discovery(discovery.with_granularity(value.parse()?)),
+            "order" => self.with_discovery(discovery.with_order(value.parse()?)),
+            "include" => self.with_discovery(discovery.with_include(value)?),
+            "exclude" => self.with_discovery(discovery.with_exclude(value)?),
+            "window" => {
+                let output = self.windowing.as_ref().map_or(WindowOutput::default(), |x| x.output);
+                self.with_windowing(Some(Windowing::new(value.parse()?, output)))
+            }
+            "window_output" => match self.windowing.clone() {
+                Some(windowing) => self.with_windowing(Some(Windowing::new(windowing.window, value.parse()?))),
+                None => return Err("The window_output option requires a window".to_string()),
+            },
+            "region" => self.with_region(Some(value.parse()?)),
+            "slices" => self.with_slices(Some(value.parse()?)),
+            "slab" => match value.trim().parse() {
+                Ok(slab) if slab > 0 => self.with_slab(Some(slab)),
+                _ => return Err(format!("Invalid slab: {}", value)),
+            },
+            _ => return Err(format!("Unknown option: {}", name)),
+        })
+    }
+
+    /// The schema of the columns read with these options: `schema()`, the tags and the `frame_index` of the slabs.
+    pub fn schema(&self) -> Schema {
+        let mut fields = schema().fields().iter().map(|x| x.as_ref().clone()).collect::<Vec<_>>();
+        for (column, _) in self.tag_columns() {
+            fields.push(Field::new(column, DataType::Utf8, true));
+        }
+        if self.slab.is_some() {
+            fields.push(Field::new("frame_index", DataType::UInt16, false));
+        }
+        Schema::new(fields)
+    }
+
+    /// The column of each tag read, with the tag, without the ones named as another column.
+    pub(crate) fn tag_columns(&self) -> Vec<(String, &str)> {
+        let mut result: Vec<(String, &str)> = Vec::new();
+        for tag in self.tags.iter() {
+            let column = tag.to_lowercase();
+            if schema().field_with_name(&column).is_err() && column != "frame_index" && result.iter().all(|(x, _)| *x != column) {
+                result.push((column, tag));
+            }
+        }
+        result
+    }
+}
+
+/// The series found in a directory
+///
+/// If there is a DICOMDIR at the root of the directory, the series are the ones it indexes.
+/// Otherwise the directory is walked, and the DICOM files in each subdirectory are a series.
+/// Zip, tar and tar.gz archives (the path itself, or the ones found walking the directory)
+/// are read without extracting them, and the DICOM files in each of their directories
+/// are a series too. Which files are read is configured with `DiscoveryOptions` (its
+/// patterns also apply to the files in the DICOMDIR).
+///
+/// In object stores (see `DicomSource`) the objects under each "directory" of the prefix
+/// are a series. DICOMDIR files and archives are not used there.
+///
+/// The series are read in the order set in `DiscoveryOptions` (by path by default), and
+/// each file is read on its own with the `Instance` granularity. The series whose first
+/// file is not an image the reader supports (see `FileHeader::check_supported`) are left
+/// out, as the files that are not DICOM files.
+#[derive(Clone)]
 pub struct DicomReader {
-    dicom_directories: Vec<String>,
+    series: Vec<Vec<FileLocation>>,
+    /// The headers of the files known (by location), from the catalog or read by the discovery,
+    /// so they are not opened again. The first file of every series has one.
+    headers: HashMap<String, FileHeader>,
+    granularity: Granularity,
+    metrics: ScanMetrics,
+}
+
+/// The `path` column of `files`: their directory, or the file itself for the `Instance` granularity.
+fn series_path(files: &[FileLocation], granularity: Granularity) -> String {
+    match granularity {
+        Granularity::Series => files[0].directory(),
+        Granularity::Instance => files[0].to_string(),
+    }
 }
 impl DicomReader {
-    pub fn new(path: impl AsRef<std::path::Path>) -> Self {
-        let mut dicom_directories = HashSet::new();
+    /// The series in `source`, or an error if it can not be listed.
+    pub fn new(source: impl Into<DicomSource>) -> io::Result<Self> {
+        DicomReader::with_discovery(source, &DiscoveryOptions::default())
+    }
 
-        for entry in walkdir::WalkDir::new(path).sort_by_file_name().into_iter().filter_map(|x| x.ok()) {
-            if entry.path().extension().is_some() && entry.path().extension().unwrap() == "dcm" {
-                dicom_directories.insert(entry.path().parent().unwrap().to_str().unwrap().to_owned());
+    /// The series in the files `(name, bytes)` (see `DicomSource::from_buffers`).
+    pub fn from_buffers<N: Into<String>, B: Into<Arc<[u8]>>>(files: impl IntoIterator<Item = (N, B)>) -> io::Result<Self> {
+        DicomReader::new(DicomSource::from_buffers(files))
+    }
+
+    /// The series in the files `(name, reader)` (see `DicomSource::from_readers`).
+    pub fn from_readers<N: Into<String>, R: Read + Seek + Send + 'static>(files: impl IntoIterator<Item = (N, R)>) -> io::Result<Self> {
+        DicomReader::new(DicomSource::from_readers(files))
+    }
+
+    pub fn with_discovery(source: impl Into<DicomSource>, discovery: &DiscoveryOptions) -> io::Result<Self> {
+        DicomReader::with_discovery_metrics(source, discovery, ScanMetrics::default())
+    }
+
+    /// Same as `with_discovery`, counting in `metrics` the files the discovery opens (to
+    /// recognize them, for the headers of the catalog, and for the header of the first file
+    /// of each series) and the series it leaves out, and then what is read from the series.
+    pub fn with_discovery_metrics(source: impl Into<DicomSource>, discovery: &DiscoveryOptions, metrics: ScanMetrics) -> io::Result<Self> {
+        // Only the files in the file system are cataloged
+        let mut catalog = None;
+        let (root, locations) = match source.into() {
+            DicomSource::Path(path) => {
+                // Patterns are relative to the directory of the archive when reading one
+                let root = if path.is_file() { path.parent().unwrap() } else { &path };
+                if let Some(mut series) = dicomdir::dicomdir_series(&path) {
+                    let root = root.display().to_string();
+                    let count = series.len();
+                    series.iter_mut().for_each(|files| files.retain(|file| discovery.matches(&root, file)));
+                    series.retain(|files| !files.is_empty());
+                    metrics.discovery_skipped.add(count - series.len());
+                    return Ok(DicomReader::from_series(series, HashMap::new(), discovery, metrics));
+                }
+                // The entries of the files no longer found are removed under the scanned directory
+                // or archive, not under the directory of the archive
+                catalog = discovery.catalog.as_ref().map(|x| (Catalog::open(x), path.display().to_string()));
+                (root.display().to_string(), DicomReader::walk(&path)?)
+            }
+            DicomSource::ObjectStore { store, prefix } => {
+                let locations = store.list(&prefix)
+                                     .map_err(|error| io::Error::new(error.kind(), format!("Can not list {}: {}", store.url(&prefix), error)))?
+                                     .into_iter()
+                                     .map(|path| FileLocation::Object { store: store.clone(), path })
+                                     .collect();
+                (store.url(&prefix), locations)
+            }
+            DicomSource::Memory(mut files) => {
+                files.sort();
+                (String::new(), files.into_iter().map(FileLocation::Memory).collect())
+            }
+            DicomSource::Files { root, mut files } => {
+                files.sort();
+                (root, files)
             }
+        };
+
+        let mut series: HashMap<String, Vec<FileLocation>> = HashMap::new();
+        let mut headers = HashMap::new();
+        // The series (or files, with the `Instance` granularity) of the files left out
+        let mut rejected = HashSet::new();
+        for location in locations {
+            let accepted = match catalog {
+                Some((ref mut catalog, _)) => discovery.accepts_with(&root, &location, |location| {
+                    let entry = catalog.entry(location, &metrics);
+                    if let Some(header) = entry.header {
+                        headers.insert(location.to_string(), header);
+                    }
+                    entry.start
+                }),
+                None => discovery.accepts_with(&root, &location, |location| {
+                    metrics.open(&metrics.header_parse_time, || file::sniff(location))
+                }),
+            };
+            if accepted {
+                series.entry(location.directory())
+                      .or_default()
+                      .push(location);
+            } else {
+                rejected.insert(series_path(&[location], discovery.granularity));
+            }
+        }
+        if let Some((mut catalog, scanned)) = catalog {
+            catalog.save(&scanned)
+                   .map_err(|error| io::Error::other(format!("Can not write the catalog {}: {}", catalog.path().display(), error)))?;
+        }
+        metrics.discovery_skipped.add(rejected.iter().filter(|x| !series.contains_key(*x)).count());
+        Ok(DicomReader::from_series(series.into_values().collect(), headers, discovery, metrics))
+    }
+
+    /// The reader of `series`, split by the granularity and sorted in the order of `discovery`
+    ///
+    /// The header of the first file of each series is read (if not in `headers`), and the
+    /// series where it is not an image the reader supports (a structured report, an RGB or
+    /// 8 bits image...) are left out, counted in `discovery_skipped`.
+    fn from_series(mut series: Vec<Vec<FileLocation>>,
+                   mut headers: HashMap<String, FileHeader>,
+                   discovery: &DiscoveryOptions,
+                   metrics: ScanMetrics) -> Self {
+        let granularity = discovery.granularity;
+        if granularity == Granularity::Instance {
+            series = series.into_iter().flatten().map(|file| vec![file]).collect();
         }
+        let count = series.len();
+        series.retain(|files| {
+            let key = files[0].to_string();
+            if !headers.contains_key(&key) {
+                match metrics.open(&metrics.header_parse_time, || FileHeader::read(&files[0])) {
+                    Ok(header) => headers.insert(key.clone(), header),
+                    Err(_) => return false,
+                };
+            }
+            headers[&key].check_supported().is_ok()
+        });
+        metrics.discovery_skipped.add(count - series.len());
+        let order = discovery.order;
+        series.sort_by_cached_key(|files| {
+            let header = headers.get(&files[0].to_string());
+            // Missing attributes last, as null values are sorted by default
+            let attributes = match (order, header) {
+                (SeriesOrder::StudyDateSeriesNumber, Some(header)) => (header.study_date.is_none(), header.study_date.clone(),
+                                                                       header.series_number.is_none(), header.series_number),
+                (SeriesOrder::Uid, Some(header)) => (header.series_instance_uid.is_none(), header.series_instance_uid.clone(),
+                                                     false, None),
+                _ => (false, None, false, None),
+            };
+            (attributes, series_path(files, granularity), files[0].clone())
+        });
         DicomReader {
-            dicom_directories: dicom_directories.into_iter().collect::<Vec<_>>(),
+            series,
+            headers,
+            granularity,
+            metrics,
         }
     }
 
-    pub fn iter(&self) -> DicomIter {
+    /// Count what is read from the series in `metrics`
+    ///
+    /// The discovery is only counted in the metrics the reader was created with (see
+    /// `with_discovery_metrics`).
+    pub fn with_metrics(mut self, metrics: ScanMetrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// The number of series, known since the discovery.
+    pub fn len(&self) -> usize {
+        self.series.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.series.is_empty()
+    }
+
+    /// The files of the series number `index`.
+    pub fn files(&self, index: usize) -> &[FileLocation] {
+        &self.series[index]
+    }
+
+    /// Only keep the series where `keep` is true, in the same order.
+    pub(crate) fn retain(&mut self, keep: &[bool]) {
+        let mut index = 0;
+        self.series.retain(|_| {
+            index += 1;
+            keep[index - 1]
+        });
+    }
+
+    /// The `path` of the series number `index`, known since the discovery.
+    pub fn path(&self, index: usize) -> String {
+        series_path(&self.series[index], self.granularity)
+    }
+
+    /// The series number `index`, with the header of its first file read by the discovery.
+    fn image(&self, index: usize) -> DicomImage {
+        let files = self.series[index].clone();
+        let header = self.headers[&files[0].to_string()].clone();
+        let mut image = DicomImage::with_header(files, header);
+        image.path = self.path(index);
+        image.metrics = self.metrics.clone();
+        image.series_index = index;
+        image
+    }
+
+    /// All the files in `path`, including the ones in the archives found, or an error if an
+    /// archive can not be listed.
+    fn walk(path: &std::path::Path) -> io::Result<Vec<FileLocation>> {
+        let mut result = Vec::new();
+        for entry in walkdir::WalkDir::new(path).sort_by_file_name().into_iter().filter_map(|x| x.ok()) {
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            match archive::ArchiveFormat::of(entry.path()) {
+                Some(_) => result.extend(archive::members(entry.path())
+                                                 .map_err(|error| io::Error::new(error.kind(), format!("Can not read {}: {}", entry.path().display(), error)))?
+                                                 .into_iter()
+                                                 .map(|member| FileLocation::ArchiveMember { archive: entry.path().to_path_buf(), member })),
+                None => result.push(FileLocation::from(entry.path())),
+            }
+        }
+        Ok(result)
+    }
+
+    pub fn iter(&self) -> DicomIter<'_> {
         DicomIter {
             dicom_reader: self,
             index: 0,
@@ -98,7 +738,7 @@ impl DicomReader {
 
 }
 
-impl<'a> IntoIterator for DicomReader {
+impl IntoIterator for DicomReader {
     type Item = DicomImage;
     type IntoIter = DicomReaderIterator;
 
@@ -119,17 +759,11 @@ impl Iterator for DicomIter<'_> {
     type Item = DicomImage;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.index >= self.dicom_reader.dicom_directories.len() {
+        if self.index >= self.dicom_reader.series.len() {
             None
         } else {
-
-            let files = std::fs::read_dir(&self.dicom_reader.dicom_directories[self.index]).unwrap();
-            let dicom_files = files.filter(|x| x.as_ref().unwrap().path().extension().unwrap() == "dcm")
-                                   .map(|x| x.unwrap().path())
-                                   .collect::<Vec<_>>();
-
             self.index += 1;
-            Some(DicomImage::new(dicom_files.iter().map(|x| x.as_path()).collect()))
+            Some(self.dicom_reader.image(self.index - 1))
         }
     }
 }
@@ -143,38 +777,68 @@ impl Iterator for DicomReaderIterator {
     type Item = DicomImage;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.index >= self.dicom_reader.dicom_directories.len() {
+        if self.index >= self.dicom_reader.series.len() {
             None
         } else {
-
-            let files = std::fs::read_dir(&self.dicom_reader.dicom_directories[self.index]).unwrap();
-            let dicom_files = files.filter(|x| x.as_ref().unwrap().path().extension().unwrap() == "dcm")
-                                   .map(|x| x.unwrap().path())
-                                   .collect::<Vec<_>>();
-
             self.index += 1;
-            Some(DicomImage::new(dicom_files.iter().map(|x| x.as_path()).collect()))
+            Some(self.dicom_reader.image(self.index - 1))
         }
     }
 }
 
 pub struct DicomStreamer {
-    row_iterator: DicomReaderIterator,
+    source: DicomSource,
+    /// Files are only discovered when the first batch is requested, with the options then set.
+    reader: Option<DicomReader>,
+    row_iterator: Option<DicomReaderIterator>,
+    /// The series (or slabs) read that did not fit in the previous batch.
+    pending: VecDeque<DicomImage>,
+    /// The index of the series of each row of the last batch.
+    batch_series: Vec<usize>,
     projection: Option<Vec<String>>,
     limit: Option<usize>,
-    remaining_limit: Option<usize>,
+    offset: usize,
+    /// The rows skipped for the offset, and the ones returned, so far.
+    skipped_rows: usize,
+    returned_rows: usize,
     batch_size: Option<usize>,
+    batch_bytes: Option<usize>,
+    reservation: Option<MemoryReservation>,
+    options: DicomOptions,
+    metrics: ScanMetrics,
 }
 
+/// The default estimated size of a batch (see `DicomStreamer::with_batch_bytes`).
+pub const DEFAULT_BATCH_BYTES: usize = 256 << 20;
+
 impl DicomStreamer {
-    pub fn new(path: impl AsRef<std::path::Path>) -> Self {
-        let reader = DicomReader::new(path);
+    /// Stream the series in the files `(name, bytes)` (see `DicomSource::from_buffers`).
+    pub fn from_buffers<N: Into<String>, B: Into<Arc<[u8]>>>(files: impl IntoIterator<Item = (N, B)>) -> Self {
+        DicomStreamer::new(DicomSource::from_buffers(files))
+    }
+
+    /// Stream the series in the files `(name, reader)` (see `DicomSource::from_readers`).
+    pub fn from_readers<N: Into<String>, R: Read + Seek + Send + 'static>(files: impl IntoIterator<Item = (N, R)>) -> Self {
+        DicomStreamer::new(DicomSource::from_readers(files))
+    }
+
+    pub fn new(source: impl Into<DicomSource>) -> Self {
         DicomStreamer {
-            row_iterator: reader.into_iter(),
+            source: source.into(),
+            reader: None,
+            row_iterator: None,
+            pending: VecDeque::new(),
+            batch_series: Vec::new(),
             projection: None,
             limit: None,
-            remaining_limit: None,
+            offset: 0,
+            skipped_rows: 0,
+            returned_rows: 0,
             batch_size: None,
+            batch_bytes: Some(DEFAULT_BATCH_BYTES),
+            reservation: None,
+            options: DicomOptions::default(),
+            metrics: ScanMetrics::default(),
         }
     }
 
@@ -187,76 +851,226 @@ impl DicomStreamer {
         self
     }
 
+    /// Return at most `limit` rows in all the batches, after the offset.
     pub fn with_limit(mut self, limit: Option<usize>) -> Self {
         self.limit = limit;
-        self.remaining_limit = limit;
         self
     }
 
+    /// Skip the first `offset` rows, as `OFFSET` in SQL
+    ///
+    /// The series skipped are not read (with slabs, their headers are read to split them).
+    pub fn with_offset(mut self, offset: usize) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    /// Return at most `batch_size` rows in each batch (at least 1).
     pub fn with_batch_size(mut self, batch_size: Option<usize>) -> Self {
-        self.batch_size = batch_size;
+        self.batch_size = batch_size.map(|x| x.max(1));
+        self
+    }
+
+    /// Cut the batches before their estimated size exceeds `batch_bytes` (`DEFAULT_BATCH_BYTES` by default)
+    ///
+    /// The size of each series is estimated before decoding it, from its columns, rows,
+    /// frames and the bytes of its voxels (see `DicomImage::estimated_size`). A series
+    /// larger than the budget is a batch on its own.
+    pub fn with_batch_bytes(mut self, batch_bytes: Option<usize>) -> Self {
+        self.batch_bytes = batch_bytes;
         self
     }
 
+    /// Reserve the estimated size of each batch in a DataFusion memory pool
+    ///
+    /// Batches are cut earlier when the pool can not grow, and the stream fails with
+    /// `ResourcesExhausted` when it can not hold a single series.
+    pub fn with_memory_reservation(mut self, reservation: MemoryReservation) -> Self {
+        self.reservation = Some(reservation);
+        self
+    }
+
+    pub fn with_options(mut self, options: DicomOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Stream the series already discovered by `reader`, instead of discovering them again
+    ///
+    /// The discovery is counted in the metrics `reader` was created with, and not in the
+    /// ones of the stream.
+    pub fn with_reader(mut self, reader: DicomReader) -> Self {
+        self.reader = Some(reader);
+        self
+    }
+
+    /// Count what the stream reads in `metrics` (a clone of it is a handle to read them).
+    pub fn with_metrics(mut self, metrics: ScanMetrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// The schema of the batches: the projected columns, in the order of `DicomOptions::schema`.
+    pub fn schema(&self) -> Schema {
+        let fields = self.options
+                         .schema()
+                         .fields()
+                         .iter()
+                         .filter(|x| self.projection.as_ref().is_none_or(|columns| columns.contains(x.name())))
+                         .map(|x| x.as_ref().clone())
+                         .collect::<Vec<_>>();
+        Schema::new(fields)
+    }
+
+    /// The series of each row of the last batch, by their index in the reader
+    ///
+    /// Each series is a row, or several consecutive ones with slabs.
+    pub(crate) fn batch_series(&self) -> &[usize] {
+        &self.batch_series
+    }
+
+    #[allow(clippy::wrong_self_convention)]
     pub fn to_record_batch(&mut self) -> Option<RecordBatch> {
-        let mut batch_iterator: Box<dyn Iterator<Item=DicomImage>> = Box::new(&mut self.row_iterator);
-
-        if let Some(limit) = self.remaining_limit {
-            if let Some(batch_size) = self.batch_size {
-                if limit < batch_size {
-                    batch_iterator = Box::new(batch_iterator.take(limit));
-                } else {
-                    (*self).remaining_limit = Some(limit - batch_size);
-                    batch_iterator = Box::new(batch_iterator.take(batch_size));
-                }
-            } else {
-                batch_iterator = Box::new(batch_iterator.take(limit));
-            }
-        } else {
-            if let Some(batch_size) = self.batch_size {
-                batch_iterator = Box::new(batch_iterator.take(batch_size));
+        self.try_next_batch().unwrap()
+    }
+
+    /// The next batch, or an error if the memory pool can not hold a single series.
+    pub fn try_next_batch(&mut self) -> Result<Option<RecordBatch>, DataFusionError> {
+        if self.row_iterator.is_none() {
+            let reader = match self.reader.take() {
+                Some(reader) => reader,
+                None => DicomReader::with_discovery_metrics(self.source.clone(), &self.options.discovery, self.metrics.clone())?,
+            };
+            let mut row_iterator = reader.with_metrics(self.metrics.clone()).into_iter();
+            // Without slabs each series is a row, so the offset skips the series without reading them
+            if self.options.slab.is_none() {
+                row_iterator.index = self.offset.min(row_iterator.dicom_reader.len());
+                self.skipped_rows = row_iterator.index;
             }
-        };
+            self.row_iterator = Some(row_iterator);
+        }
+        let row_iterator = self.row_iterator.as_mut().unwrap();
+        let remaining_rows = self.limit.map(|x| x.saturating_sub(self.returned_rows));
+        let max_rows = [self.batch_size, remaining_rows].into_iter().flatten().min();
+        if let Some(ref mut reservation) = self.reservation {
+            reservation.free();
+        }
+        self.batch_series.clear();
 
-        let (mut fetch_path,
-             mut fetch_modality,
-             mut fetch_columns,
-             mut fetch_rows,
-             mut fetch_frames,
-             mut fetch_voxels) = (true, true, true, true, true, true);
-
-        if let Some(ref columns) = self.projection {
-            let columns_set: HashSet<&str> = columns.into_iter()
-                                                    .map(|x| x.as_str())
-                                                    .collect();
-
-            let known_columns = vec!["path", "modality", "columns", "rows", "frames", "voxels"]
-                .into_iter()
-                .collect::<HashSet<_>>();
-            let unknown_columns = columns_set.difference(&known_columns).collect::<Vec<_>>();
+        let known_columns = self.options.schema();
+        let columns_set: Option<HashSet<&str>> = self.projection.as_ref().map(|columns| {
+            columns.iter()
+                   .map(|x| x.as_str())
+                   .collect()
+        });
+        if let Some(ref columns_set) = columns_set {
+            let unknown_columns = columns_set.iter()
+                                             .filter(|x| known_columns.field_with_name(x).is_err())
+                                             .collect::<Vec<_>>();
             if !unknown_columns.is_empty() {
                 panic!("Unknown columns: {:?}", unknown_columns);
             }
-            fetch_path = columns_set.contains("path");
-            fetch_modality = columns_set.contains("modality");
-            fetch_columns = columns_set.contains("columns");
-            fetch_rows = columns_set.contains("rows");
-            fetch_frames = columns_set.contains("frames");
-            fetch_voxels = columns_set.contains("voxels");
         }
+        let fetch = |name: &str| columns_set.as_ref().is_none_or(|x| x.contains(name));
+
+        let fetch_path = fetch("path");
+        let fetch_modality = fetch("modality");
+        let fetch_transfer_syntax = fetch("transfer_syntax");
+        let fetch_study_date = fetch("study_date");
+        let fetch_series_number = fetch("series_number");
+        let fetch_series_instance_uid = fetch("series_instance_uid");
+        let fetch_columns = fetch("columns");
+        let fetch_rows = fetch("rows");
+        let fetch_frames = fetch("frames");
+        let fetch_voxels = fetch("voxels");
+        let fetch_voxel_min = fetch("voxel_min");
+        let fetch_voxel_max = fetch("voxel_max");
+        let fetch_voxel_mean = fetch("voxel_mean");
+        let fetch_voxel_std = fetch("voxel_std");
+        let fetch_histogram = fetch("histogram");
+        let fetch_thumbnail = fetch("thumbnail");
+        let fetch_tags = self.options
+                             .tag_columns()
+                             .into_iter()
+                             .filter(|(column, _)| fetch(column))
+                             .collect::<Vec<_>>();
+        let fetch_frame_index = self.options.slab.is_some() && fetch("frame_index");
+        let fetch_stats = fetch_voxel_min || fetch_voxel_max || fetch_voxel_mean || fetch_voxel_std || fetch_histogram;
 
         // Can we avoid creating the builders?
         let mut path_builder = StringBuilder::new();
         let mut modality_builder = StringDictionaryBuilder::<Int16Type>::new();
+        let mut transfer_syntax_builder = StringDictionaryBuilder::<Int16Type>::new();
+        let mut study_date_builder = StringBuilder::new();
+        let mut series_number_builder = Int32Builder::new();
+        let mut series_instance_uid_builder = StringBuilder::new();
         let mut columns_builder = UInt16Builder::new();
         let mut rows_builder = UInt16Builder::new();
         let mut frames_builder = UInt16Builder::new();
         let mut voxels_builder = LargeBinaryBuilder::new();
+        let mut voxel_min_builder = Float64Builder::new();
+        let mut voxel_max_builder = Float64Builder::new();
+        let mut voxel_mean_builder = Float64Builder::new();
+        let mut voxel_std_builder = Float64Builder::new();
+        let mut histogram_builder = ListBuilder::new(UInt64Builder::new());
+        let mut thumbnail_builder = LargeBinaryBuilder::new();
+        let mut tag_builders = fetch_tags.iter().map(|_| StringBuilder::new()).collect::<Vec<_>>();
+        let mut frame_index_builder = UInt16Builder::new();
 
-        let mut empty_iterator = true;
+        let mut row_count = 0;
+        let mut batch_bytes = 0;
 
-        for dicom_image in batch_iterator {
-            empty_iterator = false;
+        while max_rows.is_none_or(|x| row_count < x) {
+            let dicom_image = match self.pending.pop_front() {
+                Some(dicom_image) => dicom_image,
+                None => {
+                    let Some(dicom_image) = row_iterator.next() else {
+                        break;
+                    };
+                    let dicom_image = match self.options.slices {
+                        Some(ref selection) => dicom_image.with_slices(selection),
+                        None => dicom_image,
+                    };
+                    let dicom_image = match self.options.region {
+                        Some(ref region) => dicom_image.with_region(region),
+                        None => dicom_image,
+                    };
+                    // The slabs of a series are read one after the other
+                    match self.options.slab {
+                        Some(slab) => {
+                            self.pending = dicom_image.slabs(slab).into();
+                            self.pending.pop_front().unwrap()
+                        }
+                        None => dicom_image,
+                    }
+                }
+            };
+            if self.skipped_rows < self.offset {
+                self.skipped_rows += 1;
+                continue;
+            }
+            // The series that would exceed the budget starts the next batch
+            let bytes = known_columns.fields()
+                                     .iter()
+                                     .filter(|x| fetch(x.name()))
+                                     .map(|x| dicom_image.estimated_size(x.name(), &self.options))
+                                     .sum::<usize>();
+            if row_count > 0 && self.batch_bytes.is_some_and(|x| batch_bytes + bytes > x) {
+                self.pending.push_front(dicom_image);
+                break;
+            }
+            if let Some(ref mut reservation) = self.reservation {
+                if let Err(error) = reservation.try_grow(bytes) {
+                    if row_count == 0 {
+                        return Err(error);
+                    }
+                    self.pending.push_front(dicom_image);
+                    break;
+                }
+            }
+            batch_bytes += bytes;
+            row_count += 1;
+            self.batch_series.push(dicom_image.series_index);
 
             if fetch_path {
                 path_builder.append_value(dicom_image.path.clone());
@@ -264,6 +1078,18 @@ impl DicomStreamer {
             if fetch_modality {
                 modality_builder.append_value(dicom_image.modality.clone());
             }
+            if fetch_transfer_syntax {
+                transfer_syntax_builder.append_value(dicom_image.transfer_syntax.clone());
+            }
+            if fetch_study_date {
+                study_date_builder.append_option(dicom_image.study_date.clone());
+            }
+            if fetch_series_number {
+                series_number_builder.append_option(dicom_image.series_number);
+            }
+            if fetch_series_instance_uid {
+                series_instance_uid_builder.append_option(dicom_image.series_instance_uid.clone());
+            }
             if fetch_columns {
                 columns_builder.append_value(dicom_image.columns.try_into().unwrap());
             }
@@ -273,52 +1099,115 @@ impl DicomStreamer {
             if fetch_frames {
                 frames_builder.append_value(dicom_image.frames.try_into().unwrap());
             }
-            if fetch_voxels {
-                let voxels = dicom_image.voxels();
-                let voxels_bytes: &[u8] = unsafe { std::slice::from_raw_parts(
-                    voxels.as_ptr() as *const u8,
-                    voxels.len() * 2,
-                ) };
-                voxels_builder.append_value(voxels_bytes);
+            if fetch_voxels || fetch_stats {
+                let mut stats = VoxelStats::new(self.options.histogram);
+                let voxels = dicom_image.voxel_bytes(self.options.windowing.as_ref(),
+                                                     fetch_voxels,
+                                                     fetch_stats.then_some(&mut stats));
+                if fetch_voxels {
+                    voxels_builder.append_value(voxels);
+                }
+                if fetch_voxel_min {
+                    voxel_min_builder.append_option(stats.min());
+                }
+                if fetch_voxel_max {
+                    voxel_max_builder.append_option(stats.max());
+                }
+                if fetch_voxel_mean {
+                    voxel_mean_builder.append_option(stats.mean());
+                }
+                if fetch_voxel_std {
+                    voxel_std_builder.append_option(stats.std());
+                }
+                if fetch_histogram {
+                    histogram_builder.append_value(stats.histogram.iter().map(|x| Some(*x)));
+                }
+            }
+            if fetch_thumbnail {
+                thumbnail_builder.append_value(dicom_image.thumbnail(&self.options.thumbnail));
+            }
+            if !fetch_tags.is_empty() {
+                let tags = fetch_tags.iter().map(|(_, tag)| *tag).collect::<Vec<_>>();
+                for (builder, value) in tag_builders.iter_mut().zip(dicom_image.tag_values(&tags)) {
+                    builder.append_option(value);
+                }
+            }
+            if fetch_frame_index {
+                frame_index_builder.append_value(dicom_image.frame_index.try_into().unwrap());
             }
         }
 
-        if empty_iterator {
-            return None;
+        if row_count == 0 {
+            return Ok(None);
         }
+        self.metrics.output_rows.add(row_count);
+        self.returned_rows += row_count;
 
         let mut fields: Vec<Field> = Vec::new();
         let mut arrays: Vec<ArrayRef> = Vec::new();
+        let mut push_column = |name: &str, array: ArrayRef| {
+            fields.push(known_columns.field_with_name(name).unwrap().clone());
+            arrays.push(array);
+        };
 
         if fetch_path {
-            fields.push(Field::new("path", DataType::Utf8, false));
-            arrays.push(Arc::new(path_builder.finish()));
+            push_column("path", Arc::new(path_builder.finish()));
         }
         if fetch_modality {
-            fields.push(Field::new("modality", DataType::Dictionary(
-                                                    Box::new(DataType::Int16),
-                                                    Box::new(DataType::Utf8)),
-                                   false));
-            arrays.push(Arc::new(modality_builder.finish()));
+            push_column("modality", Arc::new(modality_builder.finish()));
+        }
+        if fetch_transfer_syntax {
+            push_column("transfer_syntax", Arc::new(transfer_syntax_builder.finish()));
+        }
+        if fetch_study_date {
+            push_column("study_date", Arc::new(study_date_builder.finish()));
+        }
+        if fetch_series_number {
+            push_column("series_number", Arc::new(series_number_builder.finish()));
+        }
+        if fetch_series_instance_uid {
+            push_column("series_instance_uid", Arc::new(series_instance_uid_builder.finish()));
         }
         if fetch_columns {
-            fields.push(Field::new("columns", DataType::UInt16, false));
-            arrays.push(Arc::new(columns_builder.finish()));
+            push_column("columns", Arc::new(columns_builder.finish()));
         }
         if fetch_rows {
-            fields.push(Field::new("rows", DataType::UInt16, false));
-            arrays.push(Arc::new(rows_builder.finish()));
+            push_column("rows", Arc::new(rows_builder.finish()));
         }
         if fetch_frames {
-            fields.push(Field::new("frames", DataType::UInt16, false));
-            arrays.push(Arc::new(frames_builder.finish()));
+            push_column("frames", Arc::new(frames_builder.finish()));
         }
         if fetch_voxels {
-            fields.push(Field::new("voxels", DataType::LargeBinary, false));
-            arrays.push(Arc::new(voxels_builder.finish()));
+            push_column("voxels", Arc::new(voxels_builder.finish()));
+        }
+        if fetch_voxel_min {
+            push_column("voxel_min", Arc::new(voxel_min_builder.finish()));
+        }
+        if fetch_voxel_max {
+            push_column("voxel_max", Arc::new(voxel_max_builder.finish()));
+        }
+        if fetch_voxel_mean {
+            push_column("voxel_mean", Arc::new(voxel_mean_builder.finish()));
+        }
+        if fetch_voxel_std {
+            push_column("voxel_std", Arc::new(voxel_std_builder.finish()));
+        }
+        if fetch_histogram {
+            push_column("histogram", Arc::new(histogram_builder.finish()));
+        }
+        if fetch_thumbnail {
+            push_column("thumbnail", Arc::new(thumbnail_builder.finish()));
+        }
+        for ((column, _), builder) in fetch_tags.iter().zip(tag_builders.iter_mut()) {
+            push_column(column, Arc::new(builder.finish()));
+        }
+        if fetch_frame_index {
+            push_column("frame_index", Arc::new(frame_index_builder.finish()));
         }
 
-        Some(RecordBatch::try_new(Arc::new(Schema::new(fields)), arrays).unwrap())
+        // Without columns (as for `COUNT(*)`), batches only have their number of rows
+        let batch_options = RecordBatchOptions::new().with_row_count(Some(row_count));
+        Ok(Some(RecordBatch::try_new_with_options(Arc::new(Schema::new(fields)), arrays, &batch_options)?))
     }
 }
 
@@ -327,12 +1216,148 @@ impl Stream for DicomStreamer {
 
     fn poll_next(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Option<Self::Item>> {
         let self_unpinned = Pin::get_mut(self);
-        let result = self_unpinned.to_record_batch();
+        Poll::Ready(self_unpinned.try_next_batch().transpose())
+    }
+}
 
-        if let Some(record_batch) = result {
-            Poll::Ready(Some(Ok(record_batch)))
-        } else {
-            Poll::Ready(None)
+#[cfg(test)]
+mod tests {
+    use arrow::array::AsArray;
+    use arrow::datatypes::UInt16Type;
+    use super::*;
+
+    /// Series of the files of `data/tciaDownload/pat1`, of 2, 1, 2 and 1 files.
+    fn buffers() -> Vec<(String, Vec<u8>)> {
+        let files = ["1-001.dcm", "1-002.dcm"].map(|x| std::fs::read(format!("data/tciaDownload/pat1/{}", x)).unwrap());
+        [("s0", 2), ("s1", 1), ("s2", 2), ("s3", 1)].into_iter()
+                                                      .flat_map(|(series, count)| {
+                                                          files.iter().take(count).enumerate().map(move |(i, bytes)| {
+                                                              (format!("{}/{}.dcm", series, i), bytes.clone())
+                                                          })
+                                                      })
+                                                      .collect()
+    }
+
+    #[test]
+    fn limit_and_offset_across_batches() {
+        let buffers = buffers();
+        for slab in [None, Some(1)] {
+            // The path and frame index of each row, in order
+            let rows = match slab {
+                None => vec![("s0", 0), ("s1", 0), ("s2", 0), ("s3", 0)],
+                Some(_) => vec![("s0", 0), ("s0", 1), ("s1", 0), ("s2", 0), ("s2", 1), ("s3", 0)],
+            };
+            for batch_size in [Some(1), Some(2), Some(3), Some(7), None] {
+                for limit in (0..10).map(Some).chain([None]) {
+                    for offset in 0..9 {
+                        let expected = rows.iter().skip(offset).take(limit.unwrap_or(usize::MAX)).copied().collect::<Vec<_>>();
+                        let mut streamer = DicomStreamer::from_buffers(buffers.clone())
+                            .with_projection(Some(if slab.is_some() { vec!["path", "frame_index"] } else { vec!["path"] }))
+                            .with_options(DicomOptions::default().with_slab(slab))
+                            .with_batch_size(batch_size)
+                            .with_limit(limit)
+                            .with_offset(offset);
+                        let mut result = Vec::new();
+                        while let Some(batch) = streamer.try_next_batch().unwrap() {
+                            let case = (slab, batch_size, limit, offset);
+                            assert!(batch.num_rows() <= batch_size.unwrap_or(usize::MAX), "{:?}", case);
+                            let paths = batch.column_by_name("path").unwrap().as_string::<i32>();
+                            let frame_indexes = batch.column_by_name("frame_index").map(|x| x.as_primitive::<UInt16Type>());
+                            for row in 0..batch.num_rows() {
+                                result.push((paths.value(row).to_string(), frame_indexes.map_or(0, |x| x.value(row))));
+                            }
+                        }
+                        let expected = expected.iter().map(|(path, frame)| (path.to_string(), *frame)).collect::<Vec<_>>();
+                        assert_eq!(result, expected, "slab {:?}, batch size {:?}, limit {:?}, offset {}", slab, batch_size, limit, offset);
+                    }
+                }
+            }
+        }
+    }
+
+    /// `path` with its data set as is, but declared in `transfer_syntax_uid`.
+    fn declared_in(path: &str, transfer_syntax_uid: &str) -> Vec<u8> {
+        use dicom::encoding::TransferSyntaxIndex;
+        use dicom::transfer_syntax::TransferSyntaxRegistry;
+        use dicom::transfer_syntax::entries::EXPLICIT_VR_LITTLE_ENDIAN;
+
+        let dicom_file = dicom::object::open_file(path).unwrap();
+        let mut meta = dicom_file.meta().clone();
+        meta.set_transfer_syntax(TransferSyntaxRegistry.get(transfer_syntax_uid).unwrap());
+        let mut bytes = [&[0; 128][..], b"DICM"].concat();
+        meta.write(&mut bytes).unwrap();
+        dicom_file.into_inner().write_dataset_with_ts(&mut bytes, &EXPLICIT_VR_LITTLE_ENDIAN.erased()).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn unsupported_transfer_syntax() {
+        // MPEG2 can not be decoded
+        let buffers = vec![("0.dcm", std::fs::read("data/tciaDownload/pat1/1-001.dcm").unwrap()),
+                           ("1.dcm", declared_in("data/tciaDownload/pat1/1-002.dcm", "1.2.840.10008.1.2.4.100"))];
+        let metrics = ScanMetrics::new();
+        let batch = DicomStreamer::from_buffers(buffers.clone())
+            .with_projection(Some(vec!["frames", "voxels", "voxel_max", "thumbnail"]))
+            .with_metrics(metrics.clone())
+            .to_record_batch()
+            .unwrap();
+        // The voxels and the thumbnail are decoded separately
+        assert_eq!(metrics.errors_skipped.value(), 2);
+        assert_eq!(batch.column_by_name("frames").unwrap().as_primitive::<UInt16Type>().value(0), 2);
+        let voxels = batch.column_by_name("voxels").unwrap().as_binary::<i64>().value(0);
+        let first = DicomImage::from_buffers(buffers[..1].to_vec()).unwrap().voxels();
+        assert!(voxels[..2 * 512 * 512] == first.iter().flat_map(|x| x.to_le_bytes()).collect::<Vec<_>>());
+        assert!(voxels[2 * 512 * 512..].iter().all(|x| *x == 0));
+        assert_eq!(batch.column_by_name("voxel_max").unwrap().as_primitive::<arrow::datatypes::Float64Type>().value(0),
+                   *first.iter().max().unwrap() as f64);
+        // The thumbnail of the middle slice, the second one
+        assert!(batch.column_by_name("thumbnail").unwrap().as_binary::<i64>().value(0).is_empty());
+    }
+
+    /// `path` with the `elements` put, or removed when `None`.
+    fn edited(path: &str, elements: &[(dicom::core::Tag, Option<u16>)]) -> Vec<u8> {
+        use dicom::core::{DataElement, PrimitiveValue, VR};
+
+        let mut dicom_file = dicom::object::open_file(path).unwrap();
+        for (tag, value) in elements {
+            match value {
+                Some(value) => {
+                    dicom_file.put(DataElement::new(*tag, VR::US, PrimitiveValue::from(*value)));
+                }
+                None => {
+                    dicom_file.remove_element(*tag);
+                }
+            }
+        }
+        let mut bytes = Vec::new();
+        dicom_file.write_all(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn unsupported_images() {
+        let path = "data/tciaDownload/pat1/1-001.dcm";
+        let buffers = vec![("ct/0.dcm", std::fs::read(path).unwrap()),
+                           // A structured report, without pixel data
+                           ("sr/0.dcm", edited(path, &[(tags::ROWS, None), (tags::COLUMNS, None), (tags::PIXEL_DATA, None)])),
+                           ("rgb/0.dcm", edited(path, &[(tags::SAMPLES_PER_PIXEL, Some(3))])),
+                           ("u8/0.dcm", edited(path, &[(tags::BITS_ALLOCATED, Some(8))])),
+                           // Only the first file of a series is checked
+                           ("u8/1.dcm", std::fs::read(path).unwrap())];
+
+        let metrics = ScanMetrics::new();
+        let batch = DicomStreamer::from_buffers(buffers.clone())
+            .with_projection(Some(vec!["path", "frames", "voxels"]))
+            .with_metrics(metrics.clone())
+            .to_record_batch()
+            .unwrap();
+        let paths = batch.column(0).as_string::<i32>().iter().collect::<Vec<_>>();
+        assert_eq!(paths, vec![Some("ct")]);
+        assert_eq!(metrics.discovery_skipped.value(), 3);
+
+        for (name, bytes) in &buffers[1..4] {
+            assert!(DicomImage::from_buffers([(*name, bytes.clone())]).is_err(), "{}", name);
         }
+        assert!(DicomImage::from_buffers(Vec::<(String, Vec<u8>)>::new()).is_err());
     }
 }
@@ -0,0 +1,268 @@
+use std::ops::Range;
+use dicom::dictionary_std::tags;
+use crate::file::{self, FileLocation};
+use crate::metrics::ScanMetrics;
+
+/// A region of interest of the series, the voxels outside of it are not returned
+///
+/// Ranges are clamped to the size of each image. Frames are counted in the order
+/// of the files of the series, sorted by name.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Region {
+    /// Only the frames in the range, with the whole slices.
+    Frames(Range<usize>),
+    /// A bounding box of voxel indices (end not included).
+    Voxels { columns: Range<usize>, rows: Range<usize>, frames: Range<usize> },
+    /// A bounding box in patient coordinates (mm). For oblique images the
+    /// voxels of the bounding box of its corners are returned.
+    Patient { x: (f64, f64), y: (f64, f64), z: (f64, f64) },
+}
+
+/// A region resolved for a particular series
+pub(crate) struct VoxelRegion {
+    pub columns: Range<usize>,
+    pub rows: Range<usize>,
+    pub files: Vec<FileLocation>,
+}
+
+impl Region {
+    /// Resolve the region for the series of `files`
+    ///
+    /// Fails for a `Patient` region when a file does not have the position, orientation
+    /// or spacing of its slice. The headers read are counted in `metrics`.
+    pub(crate) fn resolve(&self, files: &[FileLocation], columns: usize, rows: usize, metrics: &ScanMetrics) -> Result<VoxelRegion, String> {
+        let clamp = |range: &Range<usize>, size: usize| range.start.min(size)..range.end.min(size).max(range.start.min(size));
+        match self {
+            Region::Frames(frames) => Ok(VoxelRegion {
+                columns: 0..columns,
+                rows: 0..rows,
+                files: files[clamp(frames, files.len())].to_vec(),
+            }),
+            Region::Voxels { columns: region_columns, rows: region_rows, frames } => Ok(VoxelRegion {
+                columns: clamp(region_columns, columns),
+                rows: clamp(region_rows, rows),
+                files: files[clamp(frames, files.len())].to_vec(),
+            }),
+            Region::Patient { x, y, z } => {
+                let geometries = files.iter().map(|file| SliceGeometry::read(file, metrics)).collect::<Result<Vec<_>, _>>()?;
+                // No slice left by the slice selection, nothing to resolve the region with
+                let Some(geometry) = geometries.first() else {
+                    return Ok(VoxelRegion { columns: 0..columns, rows: 0..rows, files: Vec::new() });
+                };
+                let normal = cross(geometry.row_direction, geometry.column_direction);
+                let corners = [x.0, x.1].into_iter()
+                    .flat_map(|x| [y.0, y.1].into_iter().map(move |y| (x, y)))
+                    .flat_map(|(x, y)| [z.0, z.1].into_iter().map(move |z| [x, y, z]))
+                    .map(|corner| sub(corner, geometry.position))
+                    .collect::<Vec<_>>();
+
+                let index_range = |direction: [f64; 3], spacing: f64, size: usize| {
+                    let indices = corners.iter().map(|x| dot(*x, direction) / spacing).collect::<Vec<_>>();
+                    let min = indices.iter().cloned().fold(f64::INFINITY, f64::min).floor().max(0.) as usize;
+                    let max = indices.iter().cloned().fold(f64::NEG_INFINITY, f64::max).ceil().max(-1.) + 1.;
+                    clamp(&(min..max as usize), size)
+                };
+                let distances = corners.iter().map(|x| dot(*x, normal)).collect::<Vec<_>>();
+                let min_distance = distances.iter().cloned().fold(f64::INFINITY, f64::min);
+                let max_distance = distances.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+
+                Ok(VoxelRegion {
+                    columns: index_range(geometry.row_direction, geometry.column_spacing, columns),
+                    rows: index_range(geometry.column_direction, geometry.row_spacing, rows),
+                    files: files.iter()
+                                .zip(geometries.iter())
+                                .filter(|(_, slice)| {
+                                    let distance = dot(sub(slice.position, geometry.position), normal);
+                                    min_distance <= distance && distance <= max_distance
+                                })
+                                .map(|(file, _)| file.clone())
+                                .collect(),
+                })
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for Region {
+    type Err = String;
+
+    /// Parse `frames=<start>..<end>`, `voxels=<columns>,<rows>,<frames>` with each dimension
+    /// as `<start>..<end>`, or `patient=<x>,<y>,<z>` with each dimension as `<min>..<max>` in mm.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        fn parse_range<T: std::str::FromStr>(value: &str) -> Result<(T, T), String> {
+            let (start, end) = value.split_once("..").ok_or(format!("Invalid range: {}", value))?;
+            Ok((start.trim().parse().map_err(|_| format!("Invalid range: {}", value))?,
+                end.trim().parse().map_err(|_| format!("Invalid range: {}", value))?))
+        }
+        let (kind, ranges) = value.split_once('=').ok_or(format!("Invalid region: {}", value))?;
+        let ranges = ranges.split(',').collect::<Vec<_>>();
+        match (kind.trim(), ranges.len()) {
+            ("frames", 1) => {
+                let (start, end) = parse_range(ranges[0])?;
+                Ok(Region::Frames(start..end))
+            }
+            ("voxels", 3) => {
+                let [columns, rows, frames] = [ranges[0], ranges[1], ranges[2]].map(parse_range::<usize>);
+                let (columns, rows, frames) = (columns?, rows?, frames?);
+                Ok(Region::Voxels {
+                    columns: columns.0..columns.1,
+                    rows: rows.0..rows.1,
+                    frames: frames.0..frames.1,
+                })
+            }
+            ("patient", 3) => Ok(Region::Patient {
+                x: parse_range(ranges[0])?,
+                y: parse_range(ranges[1])?,
+                z: parse_range(ranges[2])?,
+            }),
+            _ => Err(format!("Invalid region: {}", value)),
+        }
+    }
+}
+
+/// The position and orientation of a slice in patient coordinates
+struct SliceGeometry {
+    position: [f64; 3],
+    row_direction: [f64; 3],
+    column_direction: [f64; 3],
+    row_spacing: f64,
+    column_spacing: f64,
+}
+
+impl SliceGeometry {
+    fn read(file: &FileLocation, metrics: &ScanMetrics) -> Result<Self, String> {
+        let dicom_file = metrics.open(&metrics.header_parse_time, || file::open_file_header(file))
+                                .map_err(|error| format!("Can not read {}: {}", file, error))?;
+        let values = |tag, len| dicom_file.element(tag)
+                                          .ok()
+                                          .and_then(|x| x.to_multi_float64().ok())
+                                          .filter(|x| x.len() >= len)
+                                          .ok_or_else(|| format!("{} needed for patient coordinates, not found in {}", tag, file));
+        let position = values(tags::IMAGE_POSITION_PATIENT, 3)?;
+        let orientation = values(tags::IMAGE_ORIENTATION_PATIENT, 6)?;
+        let spacing = values(tags::PIXEL_SPACING, 2)?;
+        Ok(SliceGeometry {
+            position: [position[0], position[1], position[2]],
+            row_direction: [orientation[0], orientation[1], orientation[2]],
+            column_direction: [orientation[3], orientation[4], orientation[5]],
+            row_spacing: spacing[0],
+            column_spacing: spacing[1],
+        })
+    }
+}
+
+fn sub(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn dot(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow::array::AsArray;
+    use arrow::datatypes::UInt16Type;
+    use dicom::core::{DataElement, PrimitiveValue, VR};
+    use crate::metrics::ScanMetrics;
+    use crate::reader::{DicomOptions, DicomReader, DicomStreamer};
+    use super::*;
+
+    const FILES: [&str; 2] = ["data/tciaDownload/pat1/1-001.dcm", "data/tciaDownload/pat1/1-002.dcm"];
+
+    /// `path` with its slice cropped to the first `size` x `size` voxels.
+    fn smaller(path: &str, size: usize) -> Vec<u8> {
+        let mut dicom_file = dicom::object::open_file(path).unwrap();
+        let pixels = dicom_file.element(tags::PIXEL_DATA).unwrap().to_bytes().unwrap();
+        let pixels = (0..size).flat_map(|row| pixels[row * 512 * 2..(row * 512 + size) * 2].to_vec()).collect::<Vec<u8>>();
+        dicom_file.put(DataElement::new(tags::ROWS, VR::US, PrimitiveValue::from(size as u16)));
+        dicom_file.put(DataElement::new(tags::COLUMNS, VR::US, PrimitiveValue::from(size as u16)));
+        dicom_file.put(DataElement::new(tags::PIXEL_DATA, VR::OW, PrimitiveValue::from(pixels)));
+        let mut bytes = Vec::new();
+        dicom_file.write_all(&mut bytes).unwrap();
+        bytes
+    }
+
+    /// The `columns`, `rows`, `frames` and `voxels` read from `buffers` with `region`, and the metrics of the scan.
+    fn read(buffers: Vec<(String, Vec<u8>)>, region: &str) -> ((u16, u16, u16), Vec<i16>, ScanMetrics) {
+        let metrics = ScanMetrics::new();
+        let batch = DicomStreamer::from_buffers(buffers)
+            .with_projection(Some(vec!["columns", "rows", "frames", "voxels"]))
+            .with_options(DicomOptions::default().with_option("region", region).unwrap())
+            .with_metrics(metrics.clone())
+            .to_record_batch()
+            .unwrap();
+        let value = |i: usize| batch.column(i).as_primitive::<UInt16Type>().value(0);
+        let voxels = batch.column(3).as_binary::<i64>().value(0)
+                          .chunks_exact(2)
+                          .map(|x| i16::from_le_bytes([x[0], x[1]]))
+                          .collect();
+        ((value(0), value(1), value(2)), voxels, metrics)
+    }
+
+    /// The voxels of `frames` of the series in `columns` x `rows`.
+    fn crop(volume: &[i16], columns: Range<usize>, rows: Range<usize>, frames: Range<usize>) -> Vec<i16> {
+        frames.flat_map(|frame| rows.clone().map(move |row| (frame, row)))
+              .flat_map(|(frame, row)| volume[(frame * 512 + row) * 512 + columns.start..(frame * 512 + row) * 512 + columns.end].to_vec())
+              .collect()
+    }
+
+    fn buffers() -> Vec<(String, Vec<u8>)> {
+        FILES.iter().enumerate().map(|(i, path)| (format!("{}.dcm", i), std::fs::read(path).unwrap())).collect()
+    }
+
+    #[test]
+    fn voxel_and_frame_regions() {
+        let volume = DicomReader::new("data/tciaDownload").unwrap().into_iter().next().unwrap().voxels();
+
+        let (dimensions, voxels, metrics) = read(buffers(), "voxels=100..300,50..450,0..2");
+        assert_eq!((dimensions, metrics.errors_skipped.value()), ((200, 400, 2), 0));
+        assert_eq!(voxels, crop(&volume, 100..300, 50..450, 0..2));
+
+        // Clamped to the image
+        let (dimensions, voxels, _) = read(buffers(), "voxels=500..600,0..512,1..5");
+        assert_eq!(dimensions, (12, 512, 1));
+        assert_eq!(voxels, crop(&volume, 500..512, 0..512, 1..2));
+
+        let (dimensions, voxels, _) = read(buffers(), "frames=1..2");
+        assert_eq!(dimensions, (512, 512, 1));
+        assert_eq!(voxels, volume[512 * 512..]);
+    }
+
+    #[test]
+    fn patient_region() {
+        // The slices are at z = -42 and -43.25
+        let (dimensions, whole, metrics) = read(buffers(), "patient=-1000..1000,-1000..1000,-50..0");
+        assert_eq!(dimensions, (512, 512, 2));
+        let (_, voxels, voxel_metrics) = read(buffers(), "voxels=0..512,0..512,0..2");
+        assert_eq!(whole, voxels);
+        // The headers read for the geometry of the slices are counted
+        assert_eq!(metrics.files_opened.value(), voxel_metrics.files_opened.value() + 2);
+
+        let (dimensions, _, _) = read(buffers(), "patient=-1000..1000,-1000..1000,-43.5..-43");
+        assert_eq!(dimensions.2, 1);
+    }
+
+    #[test]
+    fn slices_of_another_size() {
+        let volume = DicomReader::new("data/tciaDownload").unwrap().into_iter().next().unwrap().voxels();
+        let buffers = vec![("0.dcm".to_string(), std::fs::read(FILES[0]).unwrap()),
+                           ("1.dcm".to_string(), smaller(FILES[1], 256)),
+                           ("2.dcm".to_string(), std::fs::read(FILES[1]).unwrap())];
+
+        // The smaller slice is skipped, and its frame left to zeros
+        let (dimensions, voxels, metrics) = read(buffers.clone(), "voxels=200..400,300..500,0..3");
+        assert_eq!((dimensions, metrics.errors_skipped.value()), ((200, 200, 3), 1));
+        assert_eq!(voxels[..200 * 200], crop(&volume, 200..400, 300..500, 0..1));
+        assert!(voxels[200 * 200..2 * 200 * 200].iter().all(|x| *x == 0));
+        assert_eq!(voxels[2 * 200 * 200..], crop(&volume, 200..400, 300..500, 1..2));
+
+        let (dimensions, voxels, metrics) = read(buffers, "frames=0..3");
+        assert_eq!((dimensions, metrics.errors_skipped.value()), ((512, 512, 3), 1));
+        assert_eq!(voxels.len(), 3 * 512 * 512);
+    }
+}
@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use arrow::array::{ArrayRef, AsArray, LargeBinaryBuilder, RecordBatch, UInt16Array, UInt32Array};
+use arrow::compute::{self, concat_batches};
+use arrow::datatypes::{Schema, UInt16Type};
+use arrow::error::ArrowError;
+
+/// The columns computed from the voxels of each slab, that can not be joined
+const SLAB_COLUMNS: [&str; 6] = ["voxel_min", "voxel_max", "voxel_mean", "voxel_std", "histogram", "thumbnail"];
+
+/// Join the slabs read with `DicomOptions::with_slab` back into a row per series
+///
+/// The slabs of a series (the rows with the same `path`) can be in any order and in
+/// any of `batches`, and the series are in the order of their first slab. Their
+/// `voxels` are concatenated by `frame_index` and their `frames` added, the other
+/// columns are the ones of the first slab, and `frame_index` is dropped. The `path`,
+/// `frame_index`, `frames` and `voxels` columns are required, and the columns computed
+/// from the voxels of each slab (statistics, histogram and thumbnail) can not be joined.
+pub fn reassemble_slabs(batches: &[RecordBatch]) -> Result<RecordBatch, ArrowError> {
+    let Some(first) = batches.first() else {
+        return Err(ArrowError::InvalidArgumentError("No slabs to reassemble".to_string()));
+    };
+    let batch = concat_batches(&first.schema(), batches)?;
+    if let Some(column) = SLAB_COLUMNS.iter().find(|x| batch.column_by_name(x).is_some()) {
+        return Err(ArrowError::InvalidArgumentError(format!("The column {} of the slabs can not be reassembled", column)));
+    }
+    let column = |name: &str| batch.column_by_name(name)
+                                   .ok_or_else(|| ArrowError::InvalidArgumentError(format!("The column {} is required to reassemble slabs", name)));
+    let paths = column("path")?.as_string_opt::<i32>().ok_or_else(|| ArrowError::CastError("path is not a string".to_string()))?;
+    let frame_indexes = column("frame_index")?.as_primitive_opt::<UInt16Type>()
+                                              .ok_or_else(|| ArrowError::CastError("frame_index is not a UInt16".to_string()))?;
+    let frames = column("frames")?.as_primitive_opt::<UInt16Type>()
+                                  .ok_or_else(|| ArrowError::CastError("frames is not a UInt16".to_string()))?;
+    let voxels = column("voxels")?.as_binary_opt::<i64>().ok_or_else(|| ArrowError::CastError("voxels is not a LargeBinary".to_string()))?;
+
+    // The rows of each series, in the order of their first slab
+    let mut series: Vec<Vec<usize>> = Vec::new();
+    let mut series_of_path: HashMap<&str, usize> = HashMap::new();
+    for (row, path) in paths.iter().enumerate() {
+        let index = *series_of_path.entry(path.unwrap_or_default()).or_insert_with(|| {
+            series.push(Vec::new());
+            series.len() - 1
+        });
+        series[index].push(row);
+    }
+
+    let mut voxels_builder = LargeBinaryBuilder::new();
+    let mut series_frames = Vec::with_capacity(series.len());
+    for rows in series.iter_mut() {
+        rows.sort_by_key(|row| frame_indexes.value(*row));
+        let mut next_frame = 0;
+        let mut volume = Vec::new();
+        for row in rows.iter() {
+            if frame_indexes.value(*row) != next_frame {
+                return Err(ArrowError::InvalidArgumentError(format!("The slabs of {} do not follow each other at frame {}",
+                                                                    paths.value(*row), next_frame)));
+            }
+            next_frame = next_frame.checked_add(frames.value(*row))
+                                   .ok_or_else(|| ArrowError::ComputeError(format!("Too many frames in {}", paths.value(*row))))?;
+            volume.extend_from_slice(voxels.value(*row));
+        }
+        voxels_builder.append_value(volume);
+        series_frames.push(next_frame);
+    }
+
+    let first_rows = UInt32Array::from(series.iter().map(|rows| rows[0] as u32).collect::<Vec<_>>());
+    let schema = batch.schema();
+    let mut fields = Vec::new();
+    let mut columns: Vec<ArrayRef> = Vec::new();
+    let mut voxels = Some(voxels_builder.finish());
+    for (field, array) in schema.fields().iter().zip(batch.columns()) {
+        let array: ArrayRef = match field.name().as_str() {
+            "frame_index" => continue,
+            "frames" => Arc::new(UInt16Array::from(std::mem::take(&mut series_frames))),
+            "voxels" => Arc::new(voxels.take().unwrap()),
+            _ => compute::take(array.as_ref(), &first_rows, None)?,
+        };
+        fields.push(field.clone());
+        columns.push(array);
+    }
+    RecordBatch::try_new(Arc::new(Schema::new(fields)), columns)
+}
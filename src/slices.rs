@@ -0,0 +1,130 @@
+use dicom::dictionary_std::tags;
+use crate::file::{self, FileLocation};
+use crate::metrics::ScanMetrics;
+
+/// A subset of the slices of each series to read
+///
+/// The files not selected are never decoded. Slices are in the order of the files
+/// of the series, sorted by name. Selecting by InstanceNumber reads the header
+/// of every file, but not its pixel data.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SliceSelection {
+    /// Every n-th slice, starting by the first one.
+    EveryNth(usize),
+    /// The slices with an InstanceNumber in the range (both ends included).
+    InstanceNumbers(i64, i64),
+    /// The k slices in the middle of the series.
+    Middle(usize),
+}
+
+impl SliceSelection {
+    /// The files selected, counting the headers read in `metrics`.
+    pub(crate) fn select(&self, files: Vec<FileLocation>, metrics: &ScanMetrics) -> Vec<FileLocation> {
+        match self {
+            SliceSelection::EveryNth(n) => files.into_iter().step_by((*n).max(1)).collect(),
+            SliceSelection::InstanceNumbers(first, last) => files.into_iter()
+                .filter(|file| {
+                    let instance_number = metrics.open(&metrics.header_parse_time, || file::open_file_header(file))
+                                                              .ok()
+                                                              .and_then(|x| x.element(tags::INSTANCE_NUMBER).ok()?.to_int::<i64>().ok());
+                    instance_number.is_some_and(|x| *first <= x && x <= *last)
+                })
+                .collect(),
+            SliceSelection::Middle(k) => {
+                let start = files.len().saturating_sub(*k) / 2;
+                files.into_iter().skip(start).take(*k).collect()
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for SliceSelection {
+    type Err = String;
+
+    /// Parse `every=<n>`, `instances=<first>..<last>` or `middle=<k>`.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let error = || format!("Invalid slice selection: {}", value);
+        let (kind, argument) = value.split_once('=').ok_or_else(error)?;
+        match kind.trim() {
+            "every" => Ok(SliceSelection::EveryNth(argument.trim().parse().map_err(|_| error())?)),
+            "middle" => Ok(SliceSelection::Middle(argument.trim().parse().map_err(|_| error())?)),
+            "instances" => {
+                let (first, last) = argument.split_once("..").ok_or_else(error)?;
+                Ok(SliceSelection::InstanceNumbers(first.trim().parse().map_err(|_| error())?,
+                                                   last.trim().parse().map_err(|_| error())?))
+            }
+            _ => Err(error()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow::array::AsArray;
+    use arrow::datatypes::UInt16Type;
+    use dicom::core::{DataElement, PrimitiveValue, VR};
+    use crate::metrics::ScanMetrics;
+    use crate::reader::{DicomOptions, DicomReader, DicomStreamer};
+    use super::*;
+
+    const FILES: [&str; 2] = ["data/tciaDownload/pat1/1-001.dcm", "data/tciaDownload/pat1/1-002.dcm"];
+
+    /// A series of 5 files, alternating the two files of `data/tciaDownload` and numbered from 1.
+    fn buffers() -> Vec<(String, Vec<u8>)> {
+        (0..5).map(|i| {
+            let mut dicom_file = dicom::object::open_file(FILES[i % 2]).unwrap();
+            dicom_file.put(DataElement::new(tags::INSTANCE_NUMBER, VR::IS, PrimitiveValue::from((i + 1).to_string())));
+            let mut bytes = Vec::new();
+            dicom_file.write_all(&mut bytes).unwrap();
+            (format!("{}.dcm", i), bytes)
+        }).collect()
+    }
+
+    /// The frames and voxels read with `slices`, and the files opened to decode them.
+    fn read(slices: &str) -> (u16, Vec<u8>, usize) {
+        let metrics = ScanMetrics::new();
+        let batch = DicomStreamer::from_buffers(buffers())
+            .with_projection(Some(vec!["frames", "voxels"]))
+            .with_options(DicomOptions::default().with_option("slices", slices).unwrap())
+            .with_metrics(metrics.clone())
+            .to_record_batch()
+            .unwrap();
+        (batch.column(0).as_primitive::<UInt16Type>().value(0),
+         batch.column(1).as_binary::<i64>().value(0).to_vec(),
+         metrics.files_opened.value())
+    }
+
+    #[test]
+    fn selections() {
+        let voxels = DicomReader::new("data/tciaDownload").unwrap().into_iter().next().unwrap().voxels();
+        let frames = voxels.chunks_exact(512 * 512)
+                           .map(|x| x.iter().flat_map(|x| x.to_le_bytes()).collect::<Vec<u8>>())
+                           .collect::<Vec<_>>();
+        let (a, b) = (frames[0].as_slice(), frames[1].as_slice());
+
+        // The files not selected are not opened for their pixel data
+        let all_opened = read("every=1").2;
+        let (frames, voxels, opened) = read("every=2");
+        assert_eq!((frames, opened), (3, all_opened - 2));
+        assert!(voxels == [a, a, a].concat());
+        let (frames, voxels, opened) = read("middle=2");
+        assert_eq!((frames, opened), (2, all_opened - 3));
+        assert!(voxels == [b, a].concat());
+        assert_eq!(read("middle=9").0, 5);
+        // The headers read for the instance numbers are counted
+        let (frames, voxels, opened) = read("instances=2..4");
+        assert_eq!((frames, opened), (3, all_opened - 2 + 5));
+        assert!(voxels == [b, a, b].concat());
+        assert_eq!(read("instances=6..9").0, 0);
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!("every=3".parse::<SliceSelection>().unwrap(), SliceSelection::EveryNth(3));
+        assert_eq!("instances=-1..4".parse::<SliceSelection>().unwrap(), SliceSelection::InstanceNumbers(-1, 4));
+        assert_eq!("middle = 1".parse::<SliceSelection>().unwrap(), SliceSelection::Middle(1));
+        assert!("every".parse::<SliceSelection>().is_err());
+        assert!("instances=4".parse::<SliceSelection>().is_err());
+        assert!("first=2".parse::<SliceSelection>().is_err());
+    }
+}
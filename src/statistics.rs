@@ -0,0 +1,158 @@
+/// Bins of the `histogram` column
+///
+/// The `bins` buckets have the same width and cover `lower` to `upper` (both included).
+/// Values outside of the range are not counted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HistogramOptions {
+    pub lower: f64,
+    pub upper: f64,
+    pub bins: usize,
+}
+
+impl HistogramOptions {
+    /// Fails without bins, or if `lower` and `upper` are not finite with `lower < upper`.
+    pub fn new(lower: f64, upper: f64, bins: usize) -> Result<Self, String> {
+        if bins == 0 || !lower.is_finite() || !upper.is_finite() || lower >= upper {
+            return Err(format!("Invalid histogram: {} bins from {} to {}", bins, lower, upper));
+        }
+        Ok(HistogramOptions { lower, upper, bins })
+    }
+
+    /// The bin `value` falls in, if any.
+    pub fn bin(&self, value: f64) -> Option<usize> {
+        if value < self.lower || value > self.upper || value.is_nan() {
+            return None;
+        }
+        let width = (self.upper - self.lower) / self.bins as f64;
+        Some((((value - self.lower) / width) as usize).min(self.bins - 1))
+    }
+}
+
+impl Default for HistogramOptions {
+    /// The usual CT range in HU, in bins of 16 HU.
+    fn default() -> Self {
+        HistogramOptions { lower: -1024., upper: 3071., bins: 256 }
+    }
+}
+
+/// Statistics of the voxel values, computed while they are decoded
+///
+/// Without voxels (a series without frames, or where no file could be decoded) the
+/// statistics are `None`, and the histogram is empty.
+#[derive(Debug, Clone)]
+pub struct VoxelStats {
+    min: f64,
+    max: f64,
+    count: u64,
+    sum: f64,
+    sum_squares: f64,
+    histogram_options: HistogramOptions,
+    pub histogram: Vec<u64>,
+}
+
+impl VoxelStats {
+    pub fn new(histogram_options: HistogramOptions) -> Self {
+        VoxelStats {
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            count: 0,
+            sum: 0.,
+            sum_squares: 0.,
+            histogram_options,
+            histogram: vec![0; histogram_options.bins],
+        }
+    }
+
+    pub fn update(&mut self, value: f64) {
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.count += 1;
+        self.sum += value;
+        self.sum_squares += value * value;
+        if let Some(bin) = self.histogram_options.bin(value) {
+            self.histogram[bin] += 1;
+        }
+    }
+
+    pub fn min(&self) -> Option<f64> {
+        (self.count > 0).then_some(self.min)
+    }
+
+    pub fn max(&self) -> Option<f64> {
+        (self.count > 0).then_some(self.max)
+    }
+
+    pub fn mean(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.sum / self.count as f64)
+    }
+
+    /// Population standard deviation.
+    pub fn std(&self) -> Option<f64> {
+        let mean = self.mean()?;
+        Some((self.sum_squares / self.count as f64 - mean * mean).max(0.).sqrt())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow::array::{Array, AsArray};
+    use arrow::datatypes::{Float64Type, UInt64Type};
+    use crate::reader::{DicomOptions, DicomReader, DicomStreamer};
+    use super::*;
+
+    const SERIES: &str = "data/tciaDownload";
+
+    #[test]
+    fn histogram_options() {
+        assert!(HistogramOptions::new(0., 10., 0).is_err());
+        assert!(HistogramOptions::new(10., 10., 4).is_err());
+        assert!(HistogramOptions::new(f64::NAN, 10., 4).is_err());
+        assert!(HistogramOptions::new(0., f64::INFINITY, 4).is_err());
+
+        let options = HistogramOptions::new(0., 10., 4).unwrap();
+        assert_eq!([-0.1, 0., 2.4, 2.5, 9.9, 10., 10.1, f64::NAN].map(|x| options.bin(x)),
+                   [None, Some(0), Some(0), Some(1), Some(3), Some(3), None, None]);
+    }
+
+    #[test]
+    fn without_voxels() {
+        let stats = VoxelStats::new(HistogramOptions::default());
+        assert_eq!((stats.min(), stats.max(), stats.mean(), stats.std()), (None, None, None, None));
+        assert_eq!(stats.histogram, vec![0; 256]);
+
+        // No slice selected
+        let batch = DicomStreamer::new(SERIES).with_projection(Some(vec!["voxel_min", "voxel_max", "voxel_mean", "voxel_std", "frames"]))
+                                              .with_options(DicomOptions::default().with_option("slices", "instances=10..20").unwrap())
+                                              .to_record_batch()
+                                              .unwrap();
+        assert_eq!(batch.num_rows(), 1);
+        for column in ["voxel_min", "voxel_max", "voxel_mean", "voxel_std"] {
+            assert!(batch.column_by_name(column).unwrap().is_null(0), "{}", column);
+        }
+    }
+
+    #[test]
+    fn voxel_statistics() {
+        let voxels = DicomReader::new(SERIES).unwrap().into_iter().next().unwrap().voxels();
+        let mean = voxels.iter().map(|x| *x as f64).sum::<f64>() / voxels.len() as f64;
+        let std = (voxels.iter().map(|x| (*x as f64 - mean).powi(2)).sum::<f64>() / voxels.len() as f64).sqrt();
+
+        let histogram = HistogramOptions::new(-1024., 3071., 64).unwrap();
+        let batch = DicomStreamer::new(SERIES).with_projection(Some(vec!["voxel_min", "voxel_max", "voxel_mean", "voxel_std", "histogram"]))
+                                              .with_options(DicomOptions::default().with_histogram(histogram))
+                                              .to_record_batch()
+                                              .unwrap();
+        let value = |column: &str| batch.column_by_name(column).unwrap().as_primitive::<Float64Type>().value(0);
+        assert_eq!(value("voxel_min"), *voxels.iter().min().unwrap() as f64);
+        assert_eq!(value("voxel_max"), *voxels.iter().max().unwrap() as f64);
+        assert!((value("voxel_mean") - mean).abs() < 1e-6);
+        assert!((value("voxel_std") - std).abs() < 1e-6);
+
+        let counts = batch.column_by_name("histogram").unwrap().as_list::<i32>().value(0);
+        let counts = counts.as_primitive::<UInt64Type>().values().to_vec();
+        let mut expected = vec![0; 64];
+        voxels.iter().filter_map(|x| histogram.bin(*x as f64)).for_each(|bin| expected[bin] += 1);
+        assert_eq!(counts, expected);
+        assert_eq!(counts.iter().sum::<u64>(), voxels.iter().filter(|x| (-1024..=3071).contains(*x)).count() as u64);
+    }
+}
@@ -0,0 +1,305 @@
+use std::error::Error;
+use std::future::Future;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock};
+use futures::TryStreamExt;
+use object_store::{GetOptions, GetRange, ObjectStore};
+use object_store::path::Path as ObjectPath;
+use crate::file::FileLocation;
+
+/// Where the files are discovered and read from
+#[derive(Debug, Clone)]
+pub enum DicomSource {
+    /// A directory or an archive in the local file system.
+    Path(PathBuf),
+    /// The objects under `prefix` in an object store (local, in-memory, S3...).
+    ObjectStore { store: StoreRef, prefix: ObjectPath },
+    /// Files the caller already holds, in memory or as readers.
+    Memory(Vec<MemoryFile>),
+    /// Files already listed (as by a DataFusion `ListingTable`), under `root` (displayed as
+    /// the locations are) for the discovery patterns.
+    Files { root: String, files: Vec<FileLocation> },
+}
+
+impl DicomSource {
+    /// The objects under the path of `url`, in `store`
+    ///
+    /// The url is the one of the objects in the store (e.g. `s3://bucket/prefix`), and it is
+    /// only used for the path of the objects and for the `path` column. The store needs to be
+    /// created and configured with its crate (e.g. `AmazonS3Builder::from_env()`, that
+    /// also works with MinIO and other S3 compatible storage, with the `aws` feature).
+    /// An invalid url is an error.
+    pub fn object_store(store: Arc<dyn ObjectStore>, url: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let parsed = url::Url::parse(url).map_err(|error| format!("Invalid url {}: {}", url, error))?;
+        let prefix = ObjectPath::from_url_path(parsed.path()).map_err(|error| format!("Invalid url {}: {}", url, error))?;
+        Ok(DicomSource::ObjectStore {
+            store: StoreRef {
+                store,
+                base_url: parsed[..url::Position::BeforePath].to_string(),
+            },
+            prefix,
+        })
+    }
+}
+
+impl DicomSource {
+    /// Files from the bytes of each of them, with their names.
+    ///
+    /// Names are paths, the files with the same directory (e.g. `series/1.dcm` and
+    /// `series/2.dcm`) being a series.
+    pub fn from_buffers<N: Into<String>, B: Into<Arc<[u8]>>>(files: impl IntoIterator<Item = (N, B)>) -> Self {
+        DicomSource::Memory(files.into_iter().map(|(name, bytes)| MemoryFile::from_bytes(name, bytes)).collect())
+    }
+
+    /// Files read from a reader for each of them, with their names (see `from_buffers`).
+    pub fn from_readers<N: Into<String>, R: Read + Seek + Send + 'static>(files: impl IntoIterator<Item = (N, R)>) -> Self {
+        DicomSource::Memory(files.into_iter().map(|(name, reader)| MemoryFile::from_reader(name, reader)).collect())
+    }
+}
+
+impl<T: AsRef<std::path::Path>> From<T> for DicomSource {
+    fn from(path: T) -> Self {
+        DicomSource::Path(path.as_ref().to_path_buf())
+    }
+}
+
+impl std::fmt::Display for DicomSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DicomSource::Path(path) => write!(f, "{}", path.display()),
+            DicomSource::ObjectStore { store, prefix } => write!(f, "{}", store.url(prefix)),
+            DicomSource::Memory(files) => write!(f, "{} files in memory", files.len()),
+            DicomSource::Files { root, files } if root.is_empty() => write!(f, "{} files", files.len()),
+            DicomSource::Files { root, .. } => write!(f, "{}", root),
+        }
+    }
+}
+
+/// A reader that can be shared by the files of a source
+pub trait ReadSeek: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> ReadSeek for T {}
+
+/// A file the caller holds, with the name it is displayed as
+///
+/// Files are identified by their name. Readers are seeked to their start every time
+/// the file is read, and never read concurrently.
+#[derive(Clone)]
+pub struct MemoryFile {
+    pub name: String,
+    content: MemoryContent,
+}
+
+#[derive(Clone)]
+enum MemoryContent {
+    Bytes(Arc<[u8]>),
+    Reader(Arc<Mutex<Box<dyn ReadSeek>>>),
+}
+
+impl MemoryFile {
+    pub fn from_bytes(name: impl Into<String>, bytes: impl Into<Arc<[u8]>>) -> Self {
+        MemoryFile {
+            name: name.into(),
+            content: MemoryContent::Bytes(bytes.into()),
+        }
+    }
+
+    pub fn from_reader(name: impl Into<String>, reader: impl Read + Seek + Send + 'static) -> Self {
+        MemoryFile {
+            name: name.into(),
+            content: MemoryContent::Reader(Arc::new(Mutex::new(Box::new(reader)))),
+        }
+    }
+
+    /// The content of the file, or only its first `length` bytes.
+    pub fn read(&self, length: Option<usize>) -> io::Result<Vec<u8>> {
+        match &self.content {
+            MemoryContent::Bytes(bytes) => Ok(bytes[..length.unwrap_or(bytes.len()).min(bytes.len())].to_vec()),
+            MemoryContent::Reader(reader) => {
+                let mut reader = reader.lock().unwrap();
+                reader.seek(SeekFrom::Start(0))?;
+                let mut result = Vec::new();
+                match length {
+                    Some(length) => reader.as_mut().take(length as u64).read_to_end(&mut result)?,
+                    None => reader.read_to_end(&mut result)?,
+                };
+                Ok(result)
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for MemoryFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "MemoryFile({})", self.name)
+    }
+}
+
+impl PartialEq for MemoryFile {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+impl Eq for MemoryFile {}
+
+impl std::hash::Hash for MemoryFile {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.name.hash(state);
+    }
+}
+
+impl PartialOrd for MemoryFile {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for MemoryFile {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.name.cmp(&other.name)
+    }
+}
+
+/// An object store, with the url it is found at
+///
+/// Stores are identified by their url, as their objects are displayed with it.
+#[derive(Clone)]
+pub struct StoreRef {
+    pub store: Arc<dyn ObjectStore>,
+    /// The scheme and authority of the url of the objects in the store (e.g. `s3://bucket`).
+    pub base_url: String,
+}
+
+impl StoreRef {
+    /// The url of the object `path`.
+    pub fn url(&self, path: &ObjectPath) -> String {
+        format!("{}/{}", self.base_url, path)
+    }
+
+    /// The objects under `prefix`, sorted by path.
+    pub fn list(&self, prefix: &ObjectPath) -> io::Result<Vec<ObjectPath>> {
+        let mut result = block_on(self.store.list(Some(prefix)).map_ok(|x| x.location).try_collect::<Vec<_>>())
+            .map_err(io::Error::other)?;
+        result.sort();
+        Ok(result)
+    }
+
+    /// The content of the object `path`, or only its first `length` bytes.
+    pub fn read(&self, path: &ObjectPath, length: Option<usize>) -> io::Result<Vec<u8>> {
+        let options = GetOptions {
+            range: length.map(|x| GetRange::Bounded(0..x)),
+            ..Default::default()
+        };
+        block_on(async {
+            self.store.get_opts(path, options).await?.bytes().await
+        }).map(|x| x.to_vec())
+          .map_err(io::Error::other)
+    }
+}
+
+impl std::fmt::Debug for StoreRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.base_url, self.store)
+    }
+}
+
+impl PartialEq for StoreRef {
+    fn eq(&self, other: &Self) -> bool {
+        self.base_url == other.base_url
+    }
+}
+
+impl Eq for StoreRef {}
+
+impl std::hash::Hash for StoreRef {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.base_url.hash(state);
+    }
+}
+
+impl PartialOrd for StoreRef {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for StoreRef {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.base_url.cmp(&other.base_url)
+    }
+}
+
+/// Wait for `future` from the synchronous reader
+///
+/// Inside a multi-threaded tokio runtime (as in DataFusion) the current thread is handed
+/// over while waiting, so the runtime keeps driving the requests. Otherwise (as in Polars)
+/// a runtime is created for the reader.
+fn block_on<F: Future + Send>(future: F) -> F::Output where F::Output: Send {
+    static RUNTIME: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
+    let runtime = || RUNTIME.get_or_init(|| tokio::runtime::Builder::new_multi_thread()
+                                                                  .enable_all()
+                                                                  .build()
+                                                                  .unwrap());
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == tokio::runtime::RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(|| handle.block_on(future))
+        }
+        // A runtime can not be blocked from one of its threads
+        Ok(_) => std::thread::scope(|scope| scope.spawn(|| runtime().block_on(future)).join().unwrap()),
+        Err(_) => runtime().block_on(future),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow::array::AsArray;
+    use object_store::memory::InMemory;
+    use object_store::PutPayload;
+    use crate::file;
+    use crate::reader::{DicomReader, DicomStreamer};
+    use super::*;
+
+    const SERIES: &str = "data/tciaDownload/pat1";
+
+    /// A store with two series of the files of `SERIES`, and a file that is not DICOM.
+    fn store() -> (Arc<dyn ObjectStore>, usize) {
+        let store = InMemory::new();
+        let mut size = 0;
+        for (path, file) in [("scans/a/1.dcm", "1-001.dcm"), ("scans/a/2.dcm", "1-002.dcm"), ("scans/b/1.dcm", "1-001.dcm")] {
+            let bytes = std::fs::read(PathBuf::from(SERIES).join(file)).unwrap();
+            size += bytes.len();
+            block_on(store.put(&ObjectPath::from(path), PutPayload::from(bytes))).unwrap();
+        }
+        block_on(store.put(&ObjectPath::from("scans/readme"), PutPayload::from_static(b"Not a DICOM file"))).unwrap();
+        (Arc::new(store), size)
+    }
+
+    #[test]
+    fn object_store_series() {
+        let (store, size) = store();
+        let bytes_read = file::bytes_read();
+        let batch = DicomStreamer::new(DicomSource::object_store(store.clone(), "memory:///scans").unwrap())
+            .with_projection(Some(vec!["path", "columns", "frames"]))
+            .to_record_batch()
+            .unwrap();
+        let paths = batch.column(0).as_string::<i32>().iter().flatten().collect::<Vec<_>>();
+        assert_eq!(paths, vec!["memory:///scans/a", "memory:///scans/b"]);
+        let frames = batch.column_by_name("frames").unwrap().as_primitive::<arrow::datatypes::UInt16Type>();
+        assert_eq!(frames.values().to_vec(), vec![2, 1]);
+        // Only the start of the first object of each series is read, for its header
+        assert!(((file::bytes_read() - bytes_read) as usize) < size / 4);
+
+        let image = DicomReader::new(DicomSource::object_store(store, "memory:///scans").unwrap()).unwrap().into_iter().next().unwrap();
+        let original = DicomReader::new(SERIES).unwrap().into_iter().next().unwrap();
+        assert_eq!(image.voxels(), original.voxels());
+    }
+
+    #[test]
+    fn invalid_url() {
+        let (store, _) = store();
+        assert!(DicomSource::object_store(store.clone(), "scans").is_err());
+        assert!(DicomSource::object_store(store, "memory:///scans/%FF").is_err());
+    }
+}
@@ -0,0 +1,216 @@
+use crate::windowing::{VoiTransform, Window};
+
+/// The slice the thumbnail is generated from
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ThumbnailMode {
+    /// The middle slice of the series (only this file is decoded).
+    #[default]
+    MiddleSlice,
+    /// The maximum intensity projection of all the slices along the slice axis.
+    MaximumIntensityProjection,
+}
+
+/// How the thumbnail is encoded in the `thumbnail` column
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ThumbnailFormat {
+    /// A grayscale 8 bits PNG image.
+    #[default]
+    Png,
+    /// The u8 pixels of the image, row by row.
+    Raw,
+}
+
+/// Options of the `thumbnail` column
+///
+/// The thumbnail keeps the aspect ratio of the image, with its longest side
+/// being `size` pixels. When no `window` is provided, the values of the image
+/// are scaled from its minimum to its maximum. The thumbnail of a series without
+/// slices (or where none can be decoded) is empty.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThumbnailOptions {
+    pub size: usize,
+    pub mode: ThumbnailMode,
+    pub format: ThumbnailFormat,
+    pub window: Option<Window>,
+}
+
+impl ThumbnailOptions {
+    pub fn new(size: usize) -> Self {
+        ThumbnailOptions {
+            size,
+            mode: ThumbnailMode::default(),
+            format: ThumbnailFormat::default(),
+            window: None,
+        }
+    }
+
+    pub fn with_mode(mut self, mode: ThumbnailMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn with_format(mut self, format: ThumbnailFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn with_window(mut self, window: Option<Window>) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// The width and height of the thumbnail of an image of `columns` x `rows`.
+    pub fn dimensions(&self, columns: usize, rows: usize) -> (usize, usize) {
+        let scale = (self.size as f64 / columns.max(rows) as f64).min(1.);
+        (((columns as f64 * scale).round() as usize).max(1),
+         ((rows as f64 * scale).round() as usize).max(1))
+    }
+
+    /// Downscale, window and encode an image of `columns` x `rows` values.
+    ///
+    /// The thumbnail is empty when the image is (or when `values` is not of its size,
+    /// as none of its slices could be decoded).
+    pub fn render(&self, values: &[f32], columns: usize, rows: usize, transform: Option<&VoiTransform>) -> Vec<u8> {
+        if columns == 0 || rows == 0 || values.len() != columns * rows {
+            return Vec::new();
+        }
+        let (width, height) = self.dimensions(columns, rows);
+
+        // Every thumbnail pixel is the mean of the block of the image it covers
+        let mut pixels = Vec::with_capacity(width * height);
+        for y in 0..height {
+            let (row_start, row_end) = (y * rows / height, ((y + 1) * rows / height).max(y * rows / height + 1));
+            for x in 0..width {
+                let (column_start, column_end) = (x * columns / width, ((x + 1) * columns / width).max(x * columns / width + 1));
+                let mut sum = 0.;
+                for row in row_start..row_end {
+                    sum += values[row * columns + column_start..row * columns + column_end].iter()
+                                                                                          .map(|x| *x as f64)
+                                                                                          .sum::<f64>();
+                }
+                pixels.push(sum / ((row_end - row_start) * (column_end - column_start)) as f64);
+            }
+        }
+
+        let transform = match transform {
+            Some(transform) => transform.clone(),
+            None => {
+                let min = pixels.iter().cloned().fold(f64::INFINITY, f64::min);
+                let max = pixels.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+                VoiTransform::Linear { center: (min + max) / 2., width: max - min + 1. }
+            }
+        };
+        let pixels: Vec<u8> = pixels.into_iter()
+                                    .map(|x| (transform.apply(x) * 255.).round() as u8)
+                                    .collect();
+
+        match self.format {
+            ThumbnailFormat::Raw => pixels,
+            ThumbnailFormat::Png => {
+                let mut png_bytes = Vec::new();
+                let mut encoder = png::Encoder::new(&mut png_bytes, width as u32, height as u32);
+                encoder.set_color(png::ColorType::Grayscale);
+                encoder.set_depth(png::BitDepth::Eight);
+                encoder.write_header()
+                       .and_then(|mut writer| writer.write_image_data(&pixels))
+                       .unwrap();
+                png_bytes
+            }
+        }
+    }
+}
+
+impl Default for ThumbnailOptions {
+    fn default() -> Self {
+        ThumbnailOptions::new(128)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow::array::AsArray;
+    use crate::metrics::ScanMetrics;
+    use crate::reader::{DicomOptions, DicomStreamer};
+    use crate::windowing::{WindowOutput, Windowing};
+    use super::*;
+
+    const FILES: [&str; 2] = ["data/tciaDownload/pat1/1-001.dcm", "data/tciaDownload/pat1/1-002.dcm"];
+
+    fn buffers() -> Vec<(String, Vec<u8>)> {
+        FILES.iter().enumerate().map(|(i, path)| (format!("{}.dcm", i), std::fs::read(path).unwrap())).collect()
+    }
+
+    /// The first value of `column` read from `buffers` with `options`.
+    fn read(buffers: Vec<(String, Vec<u8>)>, column: &str, options: DicomOptions) -> Vec<u8> {
+        let batch = DicomStreamer::from_buffers(buffers).with_projection(Some(vec![column]))
+                                                        .with_options(options)
+                                                        .to_record_batch()
+                                                        .unwrap();
+        batch.column(0).as_binary::<i64>().value(0).to_vec()
+    }
+
+    fn full_size(mode: ThumbnailMode) -> DicomOptions {
+        DicomOptions::default().with_thumbnail(ThumbnailOptions::new(512).with_mode(mode)
+                                                                         .with_format(ThumbnailFormat::Raw)
+                                                                         .with_window(Window::preset("abdomen")))
+    }
+
+    #[test]
+    fn middle_slice_and_projection() {
+        let windowed = read(buffers(), "voxels", DicomOptions::default().with_windowing(Some(Windowing::new(Window::preset("abdomen").unwrap(),
+                                                                                                             WindowOutput::U8))));
+        let (first, middle) = windowed.split_at(512 * 512);
+        assert_eq!(read(buffers(), "thumbnail", full_size(ThumbnailMode::MiddleSlice)), middle);
+
+        // The window is monotonic, so the projection of the windowed slices is the windowed projection
+        let projection = first.iter().zip(middle).map(|(x, y)| *x.max(y)).collect::<Vec<_>>();
+        assert_ne!(projection, middle);
+        assert_eq!(read(buffers(), "thumbnail", full_size(ThumbnailMode::MaximumIntensityProjection)), projection);
+    }
+
+    #[test]
+    fn downscaled_png() {
+        let png = read(buffers(), "thumbnail", DicomOptions::default());
+        let mut reader = png::Decoder::new(std::io::Cursor::new(&png)).read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size().unwrap()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        assert_eq!((info.width, info.height, info.color_type), (128, 128, png::ColorType::Grayscale));
+
+        let options = DicomOptions::default().with_thumbnail(ThumbnailOptions::default().with_format(ThumbnailFormat::Raw));
+        let raw = read(buffers(), "thumbnail", options);
+        assert_eq!(raw, pixels[..info.buffer_size()]);
+        // Scaled from the minimum to the maximum of the thumbnail
+        assert_eq!((raw.iter().min(), raw.iter().max()), (Some(&0), Some(&255)));
+    }
+
+    #[test]
+    fn empty_thumbnails() {
+        let options = ThumbnailOptions::default();
+        assert!(options.render(&[], 0, 512, None).is_empty());
+        assert!(options.render(&[], 512, 512, None).is_empty());
+        assert_eq!(options.dimensions(1024, 512), (128, 64));
+
+        // A region of no column
+        let region = DicomOptions::default().with_option("region", "voxels=10..10,0..512,0..2").unwrap();
+        assert!(read(buffers(), "thumbnail", region).is_empty());
+
+        // The slices of the thumbnail can not be read
+        let mut buffers = buffers();
+        buffers[1].1.truncate(4096);
+        for mode in [ThumbnailMode::MiddleSlice, ThumbnailMode::MaximumIntensityProjection] {
+            let metrics = ScanMetrics::new();
+            let batch = DicomStreamer::from_buffers(buffers.clone())
+                .with_projection(Some(vec!["thumbnail"]))
+                .with_options(DicomOptions::default().with_thumbnail(ThumbnailOptions::default().with_mode(mode)))
+                .with_metrics(metrics.clone())
+                .to_record_batch()
+                .unwrap();
+            let thumbnail = batch.column(0).as_binary::<i64>().value(0);
+            match mode {
+                ThumbnailMode::MiddleSlice => assert!(thumbnail.is_empty()),
+                ThumbnailMode::MaximumIntensityProjection => assert!(!thumbnail.is_empty()),
+            }
+            assert_eq!(metrics.errors_skipped.value(), 1);
+        }
+    }
+}
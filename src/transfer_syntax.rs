@@ -0,0 +1,76 @@
+use std::path::{Path, PathBuf};
+use dicom::transfer_syntax::TransferSyntaxRegistry;
+use dicom_pixeldata::PixelDecoder;
+use crate::file;
+use crate::discovery::DiscoveryOptions;
+
+/// Transfer syntaxes unknown to the registry of the `dicom` crate, so never supported
+const UNREGISTERED_TRANSFER_SYNTAXES: [(&str, &str); 3] = [
+    ("1.2.840.10008.1.2.4.201", "High-Throughput JPEG 2000 Image Compression (Lossless Only)"),
+    ("1.2.840.10008.1.2.4.202", "High-Throughput JPEG 2000 with RPCL Options Image Compression (Lossless Only)"),
+    ("1.2.840.10008.1.2.4.203", "High-Throughput JPEG 2000 Image Compression"),
+];
+
+/// Whether this build can decode the pixel data of a transfer syntax
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferSyntaxSupport {
+    pub uid: String,
+    pub name: String,
+    pub supported: bool,
+}
+
+/// All the transfer syntaxes known, and whether the codecs enabled in this build
+/// (see the `jpeg`, `rle` and `jpeg2000` features) can decode them.
+pub fn supported_transfer_syntaxes() -> Vec<TransferSyntaxSupport> {
+    let mut result = TransferSyntaxRegistry.iter()
+                                           .map(|ts| TransferSyntaxSupport {
+                                               uid: ts.uid().to_string(),
+                                               name: ts.name().to_string(),
+                                               supported: ts.can_decode_all(),
+                                           })
+                                           .chain(UNREGISTERED_TRANSFER_SYNTAXES.iter().map(|(uid, name)| {
+                                               TransferSyntaxSupport {
+                                                   uid: uid.to_string(),
+                                                   name: name.to_string(),
+                                                   supported: false,
+                                               }
+                                           }))
+                                           .collect::<Vec<_>>();
+    // By the numbers of the UID, so 1.2.840.10008.1.2.4.50 comes before 1.2.840.10008.1.2.4.201
+    result.sort_by_cached_key(|x| x.uid.split('.').map(|x| x.parse::<u64>().unwrap_or(u64::MAX)).collect::<Vec<_>>());
+    result
+}
+
+/// A file whose pixel data could not be decoded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UndecodableFile {
+    pub path: PathBuf,
+    /// The UID of the transfer syntax, if the file could be opened.
+    pub transfer_syntax: Option<String>,
+    pub error: String,
+}
+
+/// Try to decode the pixel data of all the DICOM files in `path`, and return the ones that fail.
+pub fn undecodable_files(path: impl AsRef<Path>) -> Vec<UndecodableFile> {
+    let discovery = DiscoveryOptions::default();
+    walkdir::WalkDir::new(&path).sort_by_file_name()
+                                .into_iter()
+                                .filter_map(|x| x.ok())
+                                .filter(|x| x.file_type().is_file() && discovery.accepts(&path.as_ref().display().to_string(), &x.path().into()))
+                                .filter_map(|entry| {
+                                    let path = entry.path().to_path_buf();
+                                    match file::open_file(&path) {
+                                        Err(error) => Some(UndecodableFile {
+                                            path,
+                                            transfer_syntax: None,
+                                            error: error.to_string(),
+                                        }),
+                                        Ok(dicom_file) => dicom_file.decode_pixel_data().err().map(|error| UndecodableFile {
+                                            transfer_syntax: file::read_transfer_syntax(&path).ok(),
+                                            path,
+                                            error: error.to_string(),
+                                        }),
+                                    }
+                                })
+                                .collect()
+}
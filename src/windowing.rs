@@ -0,0 +1,290 @@
+use dicom::dictionary_std::tags;
+use dicom::object::DefaultDicomObject;
+
+/// The window (VOI LUT) to apply to the voxel values
+///
+/// Windows are expressed in the units of the voxels after the modality LUT is applied,
+/// so in HU for CT images.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Window {
+    /// An explicit window center and width.
+    Custom { center: f64, width: f64 },
+    /// The first WindowCenter/WindowWidth pair stored in each file.
+    File,
+    /// The first item of the VOI LUT Sequence stored in each file, or its first
+    /// WindowCenter/WindowWidth pair if it does not have one.
+    LutSequence,
+}
+
+impl Window {
+    /// Usual CT windows by name (e.g. `lung`, `bone`, `brain`).
+    pub fn preset(name: &str) -> Option<Self> {
+        let (center, width) = match name.to_lowercase().as_str() {
+            "lung" => (-600., 1500.),
+            "mediastinum" => (50., 350.),
+            "abdomen" | "soft_tissue" => (40., 400.),
+            "liver" => (60., 160.),
+            "bone" => (400., 1800.),
+            "brain" => (40., 80.),
+            "subdural" => (75., 215.),
+            "stroke" => (40., 40.),
+            _ => return None,
+        };
+        Some(Window::Custom { center, width })
+    }
+
+    /// Resolve the window into the transformation to apply to the voxels of `dicom_file`
+    ///
+    /// Fails when the file does not have the window requested (nor a WindowCenter/WindowWidth
+    /// pair to fall back to for `LutSequence`), or has an invalid VOI LUT.
+    pub fn transform_for(&self, dicom_file: &DefaultDicomObject) -> Result<VoiTransform, String> {
+        match self {
+            Window::Custom { center, width } => Ok(VoiTransform::Linear { center: *center, width: *width }),
+            Window::File => {
+                let center = dicom_file.element(tags::WINDOW_CENTER)
+                                       .ok()
+                                       .and_then(|x| x.to_float64().ok());
+                let width = dicom_file.element(tags::WINDOW_WIDTH)
+                                      .ok()
+                                      .and_then(|x| x.to_float64().ok());
+                match (center, width) {
+                    (Some(center), Some(width)) => Ok(VoiTransform::Linear { center, width }),
+                    _ => Err("WindowCenter/WindowWidth not found".to_string()),
+                }
+            }
+            Window::LutSequence => {
+                let item = dicom_file.element(tags::VOILUT_SEQUENCE)
+                                     .ok()
+                                     .and_then(|x| x.items())
+                                     .and_then(|items| items.first());
+                let Some(item) = item else {
+                    return Window::File.transform_for(dicom_file)
+                                       .map_err(|_| "Neither a VOI LUT Sequence nor WindowCenter/WindowWidth found".to_string());
+                };
+                let descriptor: Vec<i64> = item.element(tags::LUT_DESCRIPTOR)
+                                               .ok()
+                                               .and_then(|x| x.to_multi_int().ok())
+                                               .filter(|x| x.len() == 3 && (1..=16).contains(&x[2]))
+                                               .ok_or_else(|| "Invalid LUT Descriptor in the VOI LUT Sequence".to_string())?;
+                let data: Vec<u16> = item.element(tags::LUT_DATA)
+                                         .ok()
+                                         .and_then(|x| x.to_multi_int().ok())
+                                         .filter(|x: &Vec<u16>| !x.is_empty())
+                                         .ok_or_else(|| "No LUT Data in the VOI LUT Sequence".to_string())?;
+                Ok(VoiTransform::Lut {
+                    first_mapped: descriptor[1] as f64,
+                    max_value: ((1u64 << descriptor[2]) - 1) as f64,
+                    data,
+                })
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for Window {
+    type Err = String;
+
+    /// Parse a preset name, `file`, `lut_sequence` or `<center>,<width>`.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "file" => Ok(Window::File),
+            "lut_sequence" => Ok(Window::LutSequence),
+            name => {
+                if let Some(window) = Window::preset(name) {
+                    return Ok(window);
+                }
+                match name.split_once(',') {
+                    Some((center, width)) => Ok(Window::Custom {
+                        center: center.trim().parse().map_err(|_| format!("Invalid window center: {}", center))?,
+                        width: width.trim().parse().map_err(|_| format!("Invalid window width: {}", width))?,
+                    }),
+                    None => Err(format!("Unknown window: {}", value)),
+                }
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Window {
+    /// As parsed by `from_str` (presets are displayed as their center and width).
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Window::Custom { center, width } => write!(f, "{},{}", center, width),
+            Window::File => write!(f, "file"),
+            Window::LutSequence => write!(f, "lut_sequence"),
+        }
+    }
+}
+
+/// The type of the windowed voxels
+///
+/// `U8` scales the window to 0-255, `F32` to 0.0-1.0.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum WindowOutput {
+    #[default]
+    U8,
+    F32,
+}
+
+impl WindowOutput {
+    pub fn bytes_per_voxel(&self) -> usize {
+        match self {
+            WindowOutput::U8 => 1,
+            WindowOutput::F32 => 4,
+        }
+    }
+}
+
+impl std::str::FromStr for WindowOutput {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "u8" => Ok(WindowOutput::U8),
+            "f32" => Ok(WindowOutput::F32),
+            _ => Err(format!("Unknown window output: {}", value)),
+        }
+    }
+}
+
+impl std::fmt::Display for WindowOutput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WindowOutput::U8 => write!(f, "u8"),
+            WindowOutput::F32 => write!(f, "f32"),
+        }
+    }
+}
+
+/// Windowing to apply to the voxels when reading them
+#[derive(Debug, Clone, PartialEq)]
+pub struct Windowing {
+    pub window: Window,
+    pub output: WindowOutput,
+}
+
+impl Windowing {
+    pub fn new(window: Window, output: WindowOutput) -> Self {
+        Windowing { window, output }
+    }
+}
+
+/// A VOI LUT transformation resolved for a particular file
+#[derive(Debug, Clone, PartialEq)]
+pub enum VoiTransform {
+    /// The linear window function (PS3.3 C.11.2.1.2.1).
+    Linear { center: f64, width: f64 },
+    /// A lookup table from a VOI LUT Sequence.
+    Lut { first_mapped: f64, max_value: f64, data: Vec<u16> },
+}
+
+impl VoiTransform {
+    /// Map a value to the 0.0-1.0 range.
+    pub fn apply(&self, value: f64) -> f64 {
+        match self {
+            VoiTransform::Linear { center, width } => {
+                if *width <= 1. {
+                    return if value < *center { 0. } else { 1. };
+                }
+                ((value - (center - 0.5)) / (width - 1.) + 0.5).clamp(0., 1.)
+            }
+            VoiTransform::Lut { first_mapped, max_value, data } => {
+                let index = (value - first_mapped).clamp(0., data.len().saturating_sub(1) as f64) as usize;
+                data.get(index).map_or(0., |x| *x as f64 / max_value)
+            }
+        }
+    }
+
+    /// Apply the transformation to `values` and return them encoded as `output`.
+    pub fn encode(&self, values: impl Iterator<Item = f64>, output: WindowOutput) -> Vec<u8> {
+        match output {
+            WindowOutput::U8 => values.map(|x| (self.apply(x) * 255.).round() as u8).collect(),
+            WindowOutput::F32 => values.flat_map(|x| (self.apply(x) as f32).to_le_bytes()).collect(),
+        }
+    }
+}
+
+/// Window voxels in the reader's encoding (little-endian i16 values), as stored in the `voxels` column.
+pub fn window_voxel_bytes(voxels: &[u8], center: f64, width: f64, output: WindowOutput) -> Vec<u8> {
+    let values = voxels.chunks_exact(2)
+                       .map(|x| i16::from_le_bytes([x[0], x[1]]) as f64);
+    VoiTransform::Linear { center, width }.encode(values, output)
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow::array::AsArray;
+    use dicom::dictionary_std::tags;
+    use crate::metrics::ScanMetrics;
+    use crate::reader::{DicomOptions, DicomStreamer};
+    use super::*;
+
+    const FILES: [&str; 2] = ["data/tciaDownload/pat1/1-001.dcm", "data/tciaDownload/pat1/1-002.dcm"];
+
+    /// The `voxels` of the series in `buffers`, read with `window`, and the errors skipped.
+    fn windowed_voxels(buffers: Vec<(String, Vec<u8>)>, window: Option<Window>) -> (Vec<u8>, usize) {
+        let metrics = ScanMetrics::new();
+        let batch = DicomStreamer::from_buffers(buffers)
+            .with_projection(Some(vec!["voxels"]))
+            .with_options(DicomOptions::default().with_windowing(window.map(|x| Windowing::new(x, WindowOutput::U8))))
+            .with_metrics(metrics.clone())
+            .to_record_batch()
+            .unwrap();
+        (batch.column(0).as_binary::<i64>().value(0).to_vec(), metrics.errors_skipped.value())
+    }
+
+    fn buffers() -> Vec<(String, Vec<u8>)> {
+        FILES.iter().enumerate().map(|(i, path)| (format!("{}.dcm", i), std::fs::read(path).unwrap())).collect()
+    }
+
+    #[test]
+    fn parse_and_display() {
+        assert_eq!("lung".parse::<Window>().unwrap(), Window::Custom { center: -600., width: 1500. });
+        assert_eq!("40, 400".parse::<Window>().unwrap(), Window::Custom { center: 40., width: 400. });
+        assert_eq!("LUT_SEQUENCE".parse::<Window>().unwrap(), Window::LutSequence);
+        assert!("40".parse::<Window>().is_err());
+        assert!("40,wide".parse::<Window>().is_err());
+        for window in [Window::File, Window::LutSequence, Window::Custom { center: -600., width: 1500. }] {
+            assert_eq!(window.to_string().parse::<Window>().unwrap(), window);
+        }
+        assert_eq!("F32".parse::<WindowOutput>().unwrap(), WindowOutput::F32);
+        assert!("u16".parse::<WindowOutput>().is_err());
+    }
+
+    #[test]
+    fn linear_window() {
+        let transform = VoiTransform::Linear { center: 40., width: 400. };
+        assert_eq!(transform.encode([-1000., -160., 40., 240., 3000.].into_iter(), WindowOutput::U8), vec![0, 0, 128, 255, 255]);
+        let narrow = VoiTransform::Linear { center: 0., width: 1. };
+        assert_eq!(narrow.encode([-1., 0., 1.].into_iter(), WindowOutput::U8), vec![0, 255, 255]);
+    }
+
+    #[test]
+    fn file_window() {
+        // The files store the abdomen window (40, 400), and the window is applied on the HU
+        let (file, errors) = windowed_voxels(buffers(), Some(Window::File));
+        assert_eq!(errors, 0);
+        assert_eq!(file, windowed_voxels(buffers(), Some(Window::preset("abdomen").unwrap())).0);
+        assert_eq!(file, windowed_voxels(buffers(), Some(Window::LutSequence)).0);
+
+        let (hu, _) = windowed_voxels(buffers(), None);
+        assert_eq!(file.len(), 2 * 512 * 512);
+        assert_eq!(file, window_voxel_bytes(&hu, 40., 400., WindowOutput::U8));
+        assert!(file.contains(&0) && file.contains(&255) && file.iter().any(|x| (1..255).contains(x)));
+    }
+
+    #[test]
+    fn missing_window_keeps_the_frames_in_place() {
+        let mut buffers = buffers();
+        let mut dicom_file = dicom::object::open_file(FILES[0]).unwrap();
+        dicom_file.remove_element(tags::WINDOW_CENTER);
+        buffers[0].1.clear();
+        dicom_file.write_all(&mut buffers[0].1).unwrap();
+
+        let (voxels, errors) = windowed_voxels(buffers.clone(), Some(Window::File));
+        assert_eq!(errors, 1);
+        assert_eq!(voxels.len(), 2 * 512 * 512);
+        assert!(voxels[..512 * 512].iter().all(|x| *x == 0));
+        assert_eq!(voxels[512 * 512..], windowed_voxels(buffers, Some(Window::preset("abdomen").unwrap())).0[512 * 512..]);
+    }
+}